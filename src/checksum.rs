@@ -0,0 +1,60 @@
+use crc::{
+    CRC_16_ARC, CRC_16_IBM_3740, CRC_16_KERMIT, CRC_16_MODBUS, CRC_16_XMODEM, CRC_32_ISCSI,
+    CRC_32_ISO_HDLC, Crc,
+};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::parser::Checksum;
+
+/// Compute a checksum, as big endian bytes
+pub fn compute(checksum: Checksum, bytes: &[u8]) -> Vec<u8> {
+    let crc16 = |algorithm| {
+        Crc::<u16>::new(algorithm)
+            .checksum(bytes)
+            .to_be_bytes()
+            .to_vec()
+    };
+    let crc32 = |algorithm| {
+        Crc::<u32>::new(algorithm)
+            .checksum(bytes)
+            .to_be_bytes()
+            .to_vec()
+    };
+    match checksum {
+        Checksum::Crc16Arc => crc16(&CRC_16_ARC),
+        Checksum::Crc16Ccitt => crc16(&CRC_16_IBM_3740),
+        Checksum::Crc16Kermit => crc16(&CRC_16_KERMIT),
+        Checksum::Crc16Modbus => crc16(&CRC_16_MODBUS),
+        Checksum::Crc16Xmodem => crc16(&CRC_16_XMODEM),
+        Checksum::Crc32 => crc32(&CRC_32_ISO_HDLC),
+        Checksum::Crc32c => crc32(&CRC_32_ISCSI),
+        Checksum::Adler32 => adler32(bytes).to_be_bytes().to_vec(),
+        Checksum::Fnv1a32 => {
+            fnv1a(bytes, 0x811c9dc5, 0x01000193, u32::MAX as u64).to_be_bytes()[4..].to_vec()
+        }
+        Checksum::Fnv1a64 => fnv1a(bytes, 0xcbf29ce484222325, 0x100000001b3, u64::MAX)
+            .to_be_bytes()
+            .to_vec(),
+        Checksum::Md5 => md5::compute(bytes).0.to_vec(),
+        Checksum::Sha1 => Sha1::digest(bytes).to_vec(),
+        Checksum::Sha256 => Sha256::digest(bytes).to_vec(),
+    }
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (a, b) = bytes.iter().fold((1, 0), |(a, b), x| {
+        let a = (a + *x as u32) % MOD;
+        (a, (b + a) % MOD)
+    });
+
+    (b << 16) | a
+}
+
+/// FNV-1a with the given offset basis and prime, truncated by `mask`
+fn fnv1a(bytes: &[u8], basis: u64, prime: u64, mask: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, x| {
+        ((hash ^ *x as u64).wrapping_mul(prime)) & mask
+    })
+}
@@ -0,0 +1,42 @@
+use crate::interpreter::{Data, Span};
+
+/// Collect the spans of every primative in the tree
+fn collect_spans(data: &Data, spans: &mut Vec<Span>) {
+    match data {
+        Data::Primative { span, .. } | Data::Padding { span, .. } => spans.push(*span),
+        Data::List { items, .. } => items.iter().for_each(|d| collect_spans(d, spans)),
+        // Left over bytes weren't understood by the pattern, so they aren't covered
+        Data::Slack { .. } => {}
+        // Decoded fields are offsets into the decoded bytes rather than the input
+        Data::Decoded { .. } => {}
+        // Covered by the integer they're part of
+        Data::Virtual { .. } | Data::Bits { .. } => {}
+    }
+}
+
+/// Find the byte ranges of the input which were never touched by any field
+pub fn find_uncovered(data: &Data, input_len: usize) -> Vec<Span> {
+    let mut spans = vec![];
+    collect_spans(data, &mut spans);
+    spans.sort_by_key(|s| s.start);
+
+    let mut uncovered = vec![];
+    let mut pos = 0;
+    for span in spans {
+        if span.start > pos {
+            uncovered.push(Span {
+                start: pos,
+                end: span.start,
+            });
+        }
+        pos = pos.max(span.end);
+    }
+    if pos < input_len {
+        uncovered.push(Span {
+            start: pos,
+            end: input_len,
+        });
+    }
+
+    uncovered
+}
@@ -1,72 +1,253 @@
-use std::fmt::Write as _;
+use std::fmt::{Display, Write as _};
 use std::io::Write;
 
 use colored::{ColoredString, Colorize};
 use rand::random;
 
-use crate::interpreter::{Data, PrimativeArray};
+use crate::interpreter::{Data, PrimativeArray, TextUnit, Value, decode_text};
+use crate::parser::EnumDecl;
 
-pub fn print_vertical(data: &Data, stack_colors: &[(u8, u8, u8)]) {
-    match data {
-        Data::Primative(primative_array) => {
-            let stack_prefix = stack_colors
-                .iter()
-                .map(|&(r, g, b)| "  ".on_truecolor(r, g, b));
+fn print_stack_prefix(stack_colors: &[(u8, u8, u8)]) {
+    let stack_prefix = stack_colors
+        .iter()
+        .map(|&(r, g, b)| "  ".on_truecolor(r, g, b));
 
-            for s in stack_prefix {
-                print!("{}", s);
-            }
+    for s in stack_prefix {
+        print!("{}", s);
+    }
+}
 
-            let string = match primative_array {
-                PrimativeArray::U8(items) => items
-                    .iter()
-                    .map(|x| format!("{x:0>2x} "))
-                    .collect::<Vec<_>>(),
-                PrimativeArray::U16(items) => {
-                    items.iter().map(|x| format!("{x} ")).collect::<Vec<_>>()
-                }
-                PrimativeArray::U32(items) => {
-                    items.iter().map(|x| format!("{x} ")).collect::<Vec<_>>()
-                }
-                PrimativeArray::U64(items) => {
-                    items.iter().map(|x| format!("{x} ")).collect::<Vec<_>>()
-                }
-                PrimativeArray::U128(items) => {
-                    items.iter().map(|x| format!("{x} ")).collect::<Vec<_>>()
-                }
-                PrimativeArray::Char(items) => items
+/// Format a value from an ENUM as its name, or one from FLAGS as the names of its set bits,
+/// flagging anything which doesn't have a name
+fn format_enum(names: &EnumDecl, value: u128) -> String {
+    if !names.flags {
+        return match names.name(value) {
+            Some(name) => format!("{} ({}) ", name, value),
+            None => format!("{} {} ", value, "(unknown)".red().bold()),
+        };
+    }
+
+    let (set, rest) = names.flags(value);
+    let mut parts = set.into_iter().map(String::from).collect::<Vec<_>>();
+    if rest != 0 {
+        parts.push(format!("{:#x}", rest).red().bold().to_string());
+    } else if parts.is_empty() {
+        parts.push("0".to_string());
+    }
+
+    format!("{} ({:#x}) ", parts.join(" | "), value)
+}
+
+/// Short form of a value from an ENUM or FLAGS, and whether every part of it has a name
+fn enum_label(names: &EnumDecl, value: u128) -> (String, bool) {
+    if !names.flags {
+        return match names.name(value) {
+            Some(name) => (name.to_string(), true),
+            None => (value.to_string(), false),
+        };
+    }
+
+    let (set, rest) = names.flags(value);
+    let mut label = set.join("|");
+    if rest != 0 {
+        if !label.is_empty() {
+            label.push('|');
+        }
+        write!(label, "{:#x}", rest).unwrap();
+    } else if label.is_empty() {
+        label.push('0');
+    }
+
+    (label, rest == 0)
+}
+
+/// Print the items of an array, eliding the middle of long arrays
+fn print_array(primative_array: &PrimativeArray, names: Option<&EnumDecl>) {
+    // Only format the items which will actually be printed
+    let format_item = |i: usize| match primative_array {
+        _ if let Some(names) = names => format_enum(names, primative_array.get(i).unwrap()),
+        PrimativeArray::U8(items) => format!("{:0>2x} ", items[i]),
+        PrimativeArray::Char(items) => {
+            format!("{} ", std::ascii::escape_default(items[i]))
+        }
+        PrimativeArray::Str(_, _, encoding) => {
+            let text = decode_text(primative_array.text().unwrap(), *encoding);
+            format!("\"{}\" ", format_text(&text))
+        }
+        _ => format!(
+            "{} ",
+            primative_array.format_int(primative_array.get(i).unwrap())
+        ),
+    };
+    let len = primative_array.len();
+    if len > 32 {
+        for i in 0..16 {
+            print!("{}", format_item(i));
+        }
+        print!("... ");
+        for i in len - 16..len {
+            print!("{}", format_item(i));
+        }
+
+        print!("(len = {})", len);
+    } else {
+        for i in 0..len {
+            print!("{}", format_item(i));
+        }
+    }
+}
+
+/// Escape decoded text for display, highlighting invalid sequences
+fn format_text(text: &[TextUnit]) -> String {
+    text.iter().fold(String::new(), |mut acc, unit| {
+        match unit {
+            TextUnit::Char(c, _) => write!(acc, "{}", c.escape_debug()).unwrap(),
+            TextUnit::Invalid(bytes) => {
+                let escaped = bytes
                     .iter()
-                    .map(|x| format!("{} ", std::ascii::escape_default(*x)))
-                    .collect::<Vec<_>>(),
-            };
-            if string.len() > 32 {
-                for s in &string[..16] {
-                    print!("{}", s);
-                }
-                print!("... ");
-                for s in &string[string.len() - 16..] {
-                    print!("{}", s);
-                }
+                    .map(|x| format!("\\x{:0>2x}", x))
+                    .collect::<String>();
+                write!(acc, "{}", escaped.red().bold()).unwrap()
+            }
+        }
 
-                print!("(len = {})", string.len());
-            } else {
-                for s in string {
-                    print!("{}", s);
+        acc
+    })
+}
+
+pub fn print_vertical(data: &Data, stack_colors: &[(u8, u8, u8)]) {
+    match data {
+        Data::Primative {
+            span,
+            identifier,
+            array: primative_array,
+            names,
+            checksum,
+        } => {
+            print_stack_prefix(stack_colors);
+            if let Some(id) = identifier {
+                print!("{}: ", id.bold());
+            }
+
+            print_array(primative_array, *names);
+            if let PrimativeArray::UBits(_, bits, ..) = primative_array {
+                let start = span.start * 8 + bits.start;
+                let end = span.start * 8 + bits.end;
+                print!("{}", format!("(bits {}..{})", start, end).dimmed());
+            }
+            match checksum {
+                Some(check) if check.valid => {
+                    print!("{}", format!("({} ok)", check.algorithm.name()).green())
                 }
+                Some(check) => print!(
+                    "{}",
+                    format!(
+                        "({} mismatch, expected {})",
+                        check.algorithm.name(),
+                        check.computed_hex()
+                    )
+                    .red()
+                    .bold()
+                ),
+                None => {}
             }
             println!();
             std::io::stdout().flush().unwrap();
         }
-        Data::List(datas) => {
+        Data::List {
+            identifier,
+            items: datas,
+            ..
+        } => {
+            if let Some(id) = identifier {
+                print_stack_prefix(stack_colors);
+                println!("{}:", id.bold());
+            }
+
             let mut stack_colors = stack_colors.to_vec();
             stack_colors.push((random(), random(), random()));
             datas
                 .iter()
                 .for_each(|data| print_vertical(data, &stack_colors));
         }
+        Data::Decoded {
+            span,
+            identifier,
+            transform,
+            bytes,
+            items,
+        } => {
+            print_stack_prefix(stack_colors);
+            let summary = format!(
+                "({}, {} -> {} bytes)",
+                transform.name(),
+                span.len(),
+                bytes.len()
+            );
+            println!("{}: {}", identifier.unwrap_or("_").bold(), summary.dimmed());
+
+            let mut stack_colors = stack_colors.to_vec();
+            stack_colors.push((random(), random(), random()));
+            items
+                .iter()
+                .for_each(|data| print_vertical(data, &stack_colors));
+        }
+        Data::Virtual {
+            identifier, value, ..
+        } => {
+            print_stack_prefix(stack_colors);
+            print!("{} = ", identifier.bold().italic());
+            match value {
+                Value::Int(x) => print!("{}", x),
+                Value::Bytes(bytes) => print!("\"{}\"", bytes.escape_ascii()),
+                Value::Array(_, array) => print_array(array, None),
+                Value::Record(..) => print!("(record)"),
+                Value::List(items) => print!("(list, len = {})", items.len()),
+            }
+            println!();
+        }
+        Data::Padding { span, .. } => {
+            print_stack_prefix(stack_colors);
+            println!("{}", format!("(padding, {} bytes)", span.len()).dimmed());
+        }
+        Data::Slack { span, .. } => {
+            print_stack_prefix(stack_colors);
+            println!("{}", format!("(slack, {} bytes)", span.len()).yellow());
+        }
+        Data::Bits {
+            identifier,
+            offset,
+            width,
+            value,
+            ..
+        } => {
+            print_stack_prefix(stack_colors);
+            print!("{}: {} ", identifier.unwrap_or("_").bold(), value);
+            let bits = match width {
+                1 => format!("bit {}", offset),
+                _ => format!("bits {}..{}", offset, offset + width),
+            };
+            println!(
+                "{}",
+                format!("({} = {:0>w$b})", bits, value, w = *width as usize).dimmed()
+            );
+        }
     }
 }
 
+/// Format bytes as hex, with each byte taking up the same width
+fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut acc, x| {
+        let hex = format!("{:0>2x}", x);
+        write!(acc, "{: <5}", hex).unwrap();
+
+        acc
+    })
+}
+
+const PADDING_COLOR: (u8, u8, u8) = (60, 60, 60);
+const SLACK_COLOR: (u8, u8, u8) = (160, 120, 0);
+
 /// Calculates whether white/black should be used for foreground text
 fn get_contrasting_color(color: (u8, u8, u8)) -> (u8, u8, u8) {
     let luminance =
@@ -84,6 +265,8 @@ pub struct HexWriter {
     hex_buffer: String,
     screen_width: usize,
     color_buffers: Vec<Vec<ColoredString>>,
+    /// Whether DECODE blocks are shown, or only the raw bytes of the input
+    show_decoded: bool,
 }
 
 impl HexWriter {
@@ -92,9 +275,15 @@ impl HexWriter {
             hex_buffer: String::new(),
             screen_width,
             color_buffers: vec![],
+            show_decoded: true,
         }
     }
 
+    pub fn with_decoded(mut self, show_decoded: bool) -> Self {
+        self.show_decoded = show_decoded;
+        self
+    }
+
     fn print(&mut self) {
         //eprintln!("hex len: {}", self.hex_buffer.len());
         //eprintln!(
@@ -143,6 +332,13 @@ impl HexWriter {
         println!();
     }
 
+    /// Print whatever is left in the buffers
+    pub fn flush(&mut self) {
+        while !self.hex_buffer.is_empty() {
+            self.print();
+        }
+    }
+
     fn check_print(&mut self) {
         if self.hex_buffer.len() >= self.screen_width {
             self.print();
@@ -150,6 +346,17 @@ impl HexWriter {
     }
 
     fn write_with_color(&mut self, hex: &str, dec: &str, color_stack: &[(u8, u8, u8)]) {
+        let fg_color = get_contrasting_color(*color_stack.iter().last().unwrap());
+        self.write_with_fg(hex, dec, color_stack, fg_color);
+    }
+
+    fn write_with_fg(
+        &mut self,
+        hex: &str,
+        dec: &str,
+        color_stack: &[(u8, u8, u8)],
+        fg_color: (u8, u8, u8),
+    ) {
         // Top up new buffers
         while self.color_buffers.len() < color_stack.len() {
             let new_buffer = if self.color_buffers.is_empty() {
@@ -164,7 +371,6 @@ impl HexWriter {
         }
 
         let color = color_stack.iter().last().unwrap();
-        let fg_color = get_contrasting_color(*color);
 
         write!(self.hex_buffer, "{}", hex).unwrap();
 
@@ -212,18 +418,51 @@ impl HexWriter {
         self.write_with_color(&hex, &dec, color_stack);
     }
 
-    fn write_u32(&mut self, val: u32, color_stack: &[(u8, u8, u8)]) {
-        let hex = val.to_le_bytes().iter().fold(String::new(), |mut acc, x| {
+    /// Write bytes which aren't valid text, highlighted in red
+    fn write_invalid(&mut self, bytes: &[u8], color_stack: &[(u8, u8, u8)]) {
+        for x in bytes {
             let hex = format!("{:0>2x}", x);
-            write!(acc, "{: <5}", hex).unwrap();
+            let hex = format!("{: <5}", hex);
+            let dec = format!("{: <5}", format!("\\x{}", hex.trim_end()));
 
-            acc
-        });
+            self.write_with_fg(&hex, &dec, color_stack, (255, 0, 0));
+        }
+    }
 
-        let dec = format!("{: <20}", val);
+    /// Write an integer shown as its bits, with the decimal value underneath
+    fn write_bits(&mut self, bits: &str, val: impl Display, color_stack: &[(u8, u8, u8)]) {
+        let val = val.to_string();
+        let width = bits.len().max(val.len()) + 1;
+        let hex = format!("{: <width$}", bits);
+        let dec = format!("{: <width$}", val);
 
         self.write_with_color(&hex, &dec, color_stack);
     }
+
+    /// Write a multi-byte integer, with the decimal value spanning all of its bytes
+    fn write_int(&mut self, bytes: &[u8], val: impl Display, color_stack: &[(u8, u8, u8)]) {
+        let hex = format_hex(bytes);
+        let dec = format!("{: <width$}", val, width = bytes.len() * 5);
+
+        self.write_with_color(&hex, &dec, color_stack);
+    }
+
+    /// Write a field as a label instead of its value, e.g. the name from an ENUM, showing as
+    /// much of it as fits and highlighting it if it isn't ok
+    fn write_label(&mut self, bytes: &[u8], label: &str, ok: bool, color_stack: &[(u8, u8, u8)]) {
+        let hex = format_hex(bytes);
+        let width = bytes.len() * 5;
+        let label = label
+            .chars()
+            .take(width.saturating_sub(1))
+            .collect::<String>();
+        let dec = format!("{: <width$}", label);
+        if ok {
+            self.write_with_color(&hex, &dec, color_stack);
+        } else {
+            self.write_with_fg(&hex, &dec, color_stack, (255, 0, 0));
+        }
+    }
 }
 
 pub fn print_horizontal(data: &Data, writer: &mut HexWriter, color_stack: &[(u8, u8, u8)]) {
@@ -234,23 +473,121 @@ pub fn print_horizontal(data: &Data, writer: &mut HexWriter, color_stack: &[(u8,
         .chain([color])
         .collect::<Vec<_>>();
     match data {
-        Data::Primative(primative_array) => match primative_array {
+        Data::Primative {
+            array: primative_array,
+            names,
+            checksum,
+            ..
+        } => match primative_array {
+            // Checksums are labelled with whether they match rather than their value
+            _ if let Some(check) = checksum => {
+                let mark = if check.valid { "ok" } else { "bad" };
+                let label = format!("{} {}", mark, check.algorithm.name());
+                writer.write_label(primative_array.bytes(), &label, check.valid, &color_stack);
+            }
+            // Bits are shown in binary, so bytes shared between fields are split between them
+            PrimativeArray::UBits(_, _, width, _) => primative_array.iter().for_each(|x| {
+                let bits = format!("{:0>width$b}", x, width = *width as usize);
+                match names {
+                    Some(names) => writer.write_bits(&bits, enum_label(names, x).0, &color_stack),
+                    None => writer.write_bits(&bits, x, &color_stack),
+                }
+            }),
+            _ if let Some(names) = names => primative_array
+                .bytes()
+                .chunks_exact(primative_array.item_size())
+                .zip(primative_array.iter())
+                .for_each(|(bytes, x)| {
+                    let (label, known) = enum_label(names, x);
+                    writer.write_label(bytes, &label, known, &color_stack);
+                }),
+            PrimativeArray::Varint(..) => primative_array
+                .varints()
+                .zip(primative_array.iter())
+                .for_each(|(bytes, x)| {
+                    writer.write_int(bytes, primative_array.format_int(x), &color_stack);
+                }),
             PrimativeArray::U8(items) => items.iter().for_each(|x| {
                 writer.write_u8(*x, &color_stack);
             }),
-            PrimativeArray::U16(items) => todo!(),
-            PrimativeArray::U32(items) => items.iter().for_each(|x| {
-                writer.write_u32(*x, &color_stack);
-            }),
-
-            PrimativeArray::U64(items) => todo!(),
-            PrimativeArray::U128(items) => todo!(),
             PrimativeArray::Char(items) => items.iter().for_each(|x| {
                 writer.write_char(*x, &color_stack);
             }),
+            PrimativeArray::Str(bytes, text, encoding) => {
+                // Length prefix
+                if text.start > 0 {
+                    let len = text.len() / encoding.unit_size();
+                    writer.write_int(&bytes[..text.start], len, &color_stack);
+                }
+                for unit in decode_text(&bytes[text.start..text.end], *encoding) {
+                    match unit {
+                        TextUnit::Char(c, bytes) => {
+                            // Escapes such as `\u{85}` can be wider than the cell for the
+                            // bytes, which would push the rest of the row out of line
+                            let mut escaped = c.escape_debug().to_string();
+                            if escaped.chars().count() >= bytes.len() * 5 {
+                                escaped = bytes.iter().map(|x| format!("\\x{:0>2x}", x)).collect();
+                            }
+                            writer.write_int(bytes, escaped, &color_stack)
+                        }
+                        TextUnit::Invalid(bytes) => writer.write_invalid(bytes, &color_stack),
+                    }
+                }
+                // Terminator or padding
+                bytes[text.end..].iter().for_each(|x| {
+                    writer.write_char(*x, &color_stack);
+                });
+            }
+            _ => primative_array
+                .bytes()
+                .chunks_exact(primative_array.item_size())
+                .zip(primative_array.iter())
+                .for_each(|(bytes, x)| {
+                    writer.write_int(bytes, x, &color_stack);
+                }),
         },
-        Data::List(datas) => datas
+        Data::List { items: datas, .. } => datas
             .iter()
             .for_each(|d| print_horizontal(d, writer, &color_stack)),
+        Data::Padding { bytes, .. } => {
+            // Padding is always grey rather than a random colour
+            let color_stack = [&color_stack[..color_stack.len() - 1], &[PADDING_COLOR]].concat();
+            bytes.iter().for_each(|x| writer.write_u8(*x, &color_stack));
+        }
+        Data::Decoded {
+            span,
+            transform,
+            bytes,
+            items,
+            ..
+        } if writer.show_decoded => {
+            // Decoded bytes have their own offsets, so they're shown as a separate dump
+            // where the stream is used rather than mixed in with the input
+            writer.flush();
+            let header = format!(
+                "{} stream at {:#x}..{:#x}, {} bytes decoded:",
+                transform.name(),
+                span.start,
+                span.end,
+                bytes.len()
+            );
+            println!("{}", header.dimmed());
+            let mut decoded_writer = HexWriter::new(writer.screen_width);
+            items
+                .iter()
+                .for_each(|d| print_horizontal(d, &mut decoded_writer, &color_stack));
+            decoded_writer.flush();
+            println!("{}", format!("end of {} stream", transform.name()).dimmed());
+        }
+        // The raw bytes of the field have already been shown
+        Data::Decoded { .. } => {}
+        Data::Slack { bytes, .. } => {
+            // Left over bytes are highlighted so that a desynced pattern stands out
+            let color_stack = [&color_stack[..color_stack.len() - 1], &[SLACK_COLOR]].concat();
+            bytes.iter().for_each(|x| writer.write_u8(*x, &color_stack));
+        }
+        // Virtual fields don't have any bytes to show, and bit fields are shown by the
+        // integer they're part of
+        Data::Virtual { .. } | Data::Bits { .. } => {}
     }
 }
@@ -0,0 +1,106 @@
+use serde_json::{Map, Value, json};
+
+use crate::interpreter::{Data, PrimativeArray, Value as StackValue};
+
+/// Convert a parsed tree to JSON. Lists with named fields become objects, other lists
+/// become arrays, and single item primatives become scalars.
+pub fn to_json(data: &Data) -> Value {
+    match data {
+        Data::Primative {
+            array,
+            names: Some(names),
+            ..
+        } => {
+            let mut values = array.iter().map(|x| {
+                if !names.flags {
+                    return names
+                        .name(x)
+                        .map_or_else(|| int_to_json(x), |name| json!(name));
+                }
+
+                // Flags become a list of names, with any unnamed bits as a number at the end
+                let (set, rest) = names.flags(x);
+                let mut flags = set.into_iter().map(|name| json!(name)).collect::<Vec<_>>();
+                if rest != 0 {
+                    flags.push(int_to_json(rest));
+                }
+                Value::Array(flags)
+            });
+            if array.len() == 1 {
+                values.next().unwrap()
+            } else {
+                Value::Array(values.collect())
+            }
+        }
+        Data::Primative { array, .. } => array_to_json(array),
+        Data::List { items, .. } | Data::Decoded { items, .. } => {
+            if items.iter().any(|d| d.identifier().is_some()) {
+                let fields = items
+                    .iter()
+                    .filter_map(|d| Some((d.identifier()?.to_string(), to_json(d))))
+                    .collect::<Map<_, _>>();
+
+                Value::Object(fields)
+            } else {
+                Value::Array(items.iter().map(to_json).collect())
+            }
+        }
+        Data::Virtual { value, .. } => match value {
+            StackValue::Int(x) => json!(x),
+            StackValue::Bytes(bytes) => json!(String::from_utf8_lossy(bytes)),
+            StackValue::Array(_, array) => array_to_json(array),
+            StackValue::Record(..) | StackValue::List(_) => Value::Null,
+        },
+        Data::Bits { value, .. } => int_to_json(*value),
+        Data::Padding { .. } | Data::Slack { .. } => Value::Null,
+    }
+}
+
+fn array_to_json(array: &PrimativeArray) -> Value {
+    match array {
+        PrimativeArray::Char(items) => json!(String::from_utf8_lossy(items)),
+        PrimativeArray::Str(..) => json!(array.string().unwrap()),
+        _ => {
+            let signed = array.is_signed();
+            let mut values = array.iter().map(|x| match signed {
+                true => signed_to_json(x as i128),
+                false => int_to_json(x),
+            });
+            if array.len() == 1 {
+                values.next().unwrap()
+            } else {
+                Value::Array(values.collect())
+            }
+        }
+    }
+}
+
+fn int_to_json(x: u128) -> Value {
+    match u64::try_from(x) {
+        Ok(x) => json!(x),
+        // JSON numbers can't hold the full u128 range
+        Err(_) => json!(x.to_string()),
+    }
+}
+
+fn signed_to_json(x: i128) -> Value {
+    match i64::try_from(x) {
+        Ok(x) => json!(x),
+        Err(_) => json!(x.to_string()),
+    }
+}
+
+/// Collect the raw input bytes of every primative in the tree, in order
+pub fn raw_bytes(data: &Data, bytes: &mut Vec<u8>) {
+    match data {
+        Data::Primative { array, .. } => bytes.extend_from_slice(array.bytes()),
+        Data::Padding { bytes: padding, .. } | Data::Slack { bytes: padding, .. } => {
+            bytes.extend_from_slice(padding)
+        }
+        Data::List { items, .. } => items.iter().for_each(|d| raw_bytes(d, bytes)),
+        // The raw bytes of a decoded stream are the decoded bytes, rather than the
+        // compressed field they came from
+        Data::Decoded { bytes: decoded, .. } => bytes.extend_from_slice(decoded),
+        Data::Virtual { .. } | Data::Bits { .. } => {}
+    }
+}
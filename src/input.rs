@@ -0,0 +1,42 @@
+use std::{fs, ops::Deref};
+
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+
+/// The bytes of the binary file, either read into memory or mapped from disk
+pub enum Input {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Input {
+    /// Read the whole file into memory
+    pub fn read(path: &str) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+
+        Ok(Self::Owned(bytes))
+    }
+
+    /// Map the file into memory, so only the pages which are touched get loaded
+    pub fn map(path: &str) -> Result<Self> {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+
+        // SAFETY: The file is only ever read, but the mapping becomes undefined if another
+        // process truncates or modifies it while we're parsing
+        let mmap =
+            unsafe { Mmap::map(&file) }.with_context(|| format!("Failed to map {:?}", path))?;
+
+        Ok(Self::Mapped(mmap))
+    }
+}
+
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Input::Owned(bytes) => bytes,
+            Input::Mapped(mmap) => mmap,
+        }
+    }
+}
@@ -1,309 +1,2106 @@
 use anyhow::{Context, Result, bail};
-use std::{collections::HashMap, iter::Peekable};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
-use crate::parser::{Count, DType, Endianness, Expr};
+use crate::parser::{
+    BinOp, BitField, Checksum, Count, DType, Encoding, Endianness, EnumDecl, Expr, Transform,
+    ValueExpr, Varint,
+};
+use crate::{checksum, transform};
 
+/// A run of primatives, borrowed from the input and decoded on demand
+#[derive(Debug, Clone, Copy)]
+pub enum PrimativeArray<'b> {
+    U8(&'b [u8]),
+    U16(&'b [u8], Endianness),
+    U32(&'b [u8], Endianness),
+    U64(&'b [u8], Endianness),
+    U128(&'b [u8], Endianness),
+    Char(&'b [u8]),
+    /// A single string, with the range of its text within the raw bytes
+    Str(&'b [u8], Span, Encoding),
+    /// Integers of any number of bits, with the range of bits they take within the raw bytes
+    UBits(&'b [u8], Span, u32, Endianness),
+    /// Variable length integers. Signed values are stored as two's complement.
+    Varint(&'b [u8], Varint),
+}
+
+/// The variables set by a block, by name
+pub type Record<'a, 'b> = HashMap<&'a str, Value<'a, 'b>>;
+
+/// Bytes made while parsing, such as decoded streams. The parsed tree borrows from them like
+/// it does from the input, so they're owned by the caller and outlive the run.
+pub type Buffers = typed_arena::Arena<Vec<u8>>;
+
+/// A value held on the stack
+#[derive(Debug, Clone)]
+pub enum Value<'a, 'b> {
+    Int(i128),
+    /// Bytes from a literal in the pattern
+    Bytes(Rc<[u8]>),
+    /// A field, and the bytes it covers
+    Array(Span, PrimativeArray<'b>),
+    /// The fields of a single pass through a block, and the bytes it covered
+    Record(Span, &'b [u8], Rc<Record<'a, 'b>>),
+    /// Every pass through a named block
+    List(Rc<Vec<Value<'a, 'b>>>),
+}
+
+impl Value<'_, '_> {
+    /// Use the value as a single integer. Arrays are represented by their first item.
+    fn as_int(&self) -> Result<i128> {
+        match self {
+            Value::Int(x) => Ok(*x),
+            Value::Array(_, array) if array.text().is_some() => {
+                bail!("Cannot use dtype as integer: {:?}", array)
+            }
+            Value::Array(_, array) => {
+                let val = array.get(0).context("Array is empty")?;
+                if array.is_signed() {
+                    return Ok(val as i128);
+                }
+                i128::try_from(val).with_context(|| format!("Cannot downcast {} -> i128", val))
+            }
+            Value::Bytes(_) => bail!("Cannot use a string as an integer"),
+            Value::Record(..) | Value::List(_) => bail!("Cannot use a block as an integer"),
+        }
+    }
+
+    /// Number of bytes taken up by the value
+    fn size(&self) -> Result<usize> {
+        match self {
+            Value::Int(_) => bail!("Derived values don't have a size"),
+            Value::Bytes(bytes) => Ok(bytes.len()),
+            Value::Array(_, array) => Ok(array.bytes().len()),
+            Value::Record(span, ..) => Ok(span.len()),
+            Value::List(items) => items.iter().map(|x| x.size()).sum(),
+        }
+    }
+
+    /// Compare two values. Strings are compared as UTF-8 against the raw bytes of the
+    /// other side, anything else as integers.
+    fn equals(&self, other: &Self) -> Result<bool> {
+        if !(self.is_text() || other.is_text()) {
+            return Ok(self.as_int()? == other.as_int()?);
+        }
+
+        match (self.raw_bytes(), other.raw_bytes()) {
+            (Some(lhs), Some(rhs)) => Ok(*lhs == *rhs),
+            _ => bail!("Cannot compare {:?} with {:?}", self, other),
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        match self {
+            Value::Bytes(_) => true,
+            Value::Array(_, array) => array.text().is_some(),
+            _ => false,
+        }
+    }
+
+    /// The bytes behind a string or array. Strings don't include their length prefix or
+    /// terminator, and are converted to UTF-8 if they use a different encoding.
+    fn raw_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Value::Bytes(bytes) => Some(Cow::Borrowed(bytes)),
+            Value::Array(
+                _,
+                array @ PrimativeArray::Str(_, _, Encoding::Utf16(_) | Encoding::Latin1),
+            ) => Some(Cow::Owned(array.string().unwrap().into_bytes())),
+            Value::Array(_, array) => Some(Cow::Borrowed(array.text().unwrap_or(array.bytes()))),
+            _ => None,
+        }
+    }
+
+    /// Short human readable form, for error messages
+    fn describe(&self) -> String {
+        match self {
+            Value::Int(x) => x.to_string(),
+            Value::Bytes(bytes) => format!("b\"{}\"", bytes.escape_ascii()),
+            Value::Array(_, array @ PrimativeArray::Str(..)) => {
+                format!("{:?}", array.string().unwrap())
+            }
+            Value::Array(_, array @ PrimativeArray::Char(_)) => {
+                format!("b\"{}\"", array.bytes().escape_ascii())
+            }
+            Value::Array(_, array) if array.len() == 1 => array.format_int(array.get(0).unwrap()),
+            Value::Array(_, array @ PrimativeArray::U8(_)) => {
+                format!("b\"{}\"", array.bytes().escape_ascii())
+            }
+            Value::Array(_, array) => format!(
+                "[{}]",
+                array
+                    .iter()
+                    .map(|x| array.format_int(x))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Record(..) => "(record)".to_string(),
+            Value::List(items) => format!("(list, len = {})", items.len()),
+        }
+    }
+}
+
+/// A half-open range of byte offsets into the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// The result of comparing a field declared with CHECKSUM to the checksum of the bytes it
+/// covers
 #[derive(Debug, Clone)]
-pub enum PrimativeArray {
-    U8(Vec<u8>),
-    U16(Vec<u16>),
-    U32(Vec<u32>),
-    U64(Vec<u64>),
-    U128(Vec<u128>),
-    Char(Vec<u8>),
+pub struct ChecksumCheck {
+    pub algorithm: Checksum,
+    /// The checksum of the covered bytes, big endian
+    pub computed: Vec<u8>,
+    pub valid: bool,
 }
 
+impl ChecksumCheck {
+    /// The computed checksum in hex
+    pub fn computed_hex(&self) -> String {
+        self.computed
+            .iter()
+            .map(|x| format!("{:0>2x}", x))
+            .collect()
+    }
+}
+
+/// A node of the parsed tree. Identifiers are borrowed from the pattern, values from the input
 #[derive(Debug, Clone)]
-pub enum Data {
-    Primative(PrimativeArray),
-    List(Vec<Data>),
+pub enum Data<'a, 'b> {
+    Primative {
+        span: Span,
+        identifier: Option<&'a str>,
+        array: PrimativeArray<'b>,
+        /// Names for the values, if the dtype was declared with ENUM or FLAGS
+        names: Option<&'a EnumDecl>,
+        /// Whether the field matches, if it was declared as a CHECKSUM
+        checksum: Option<ChecksumCheck>,
+    },
+    List {
+        span: Span,
+        identifier: Option<&'a str>,
+        items: Vec<Data<'a, 'b>>,
+    },
+    /// A derived value which doesn't take up any bytes
+    Virtual {
+        span: Span,
+        identifier: &'a str,
+        value: Value<'a, 'b>,
+    },
+    /// Bytes skipped by ALIGN or PAD
+    Padding { span: Span, bytes: &'b [u8] },
+    /// Bytes left over at the end of a LIMIT block
+    Slack { span: Span, bytes: &'b [u8] },
+    /// Fields parsed from the decoded contents of a field. The span is the original field,
+    /// and the spans of the items are offsets into the decoded bytes.
+    Decoded {
+        span: Span,
+        identifier: Option<&'a str>,
+        transform: &'a Transform,
+        bytes: &'b [u8],
+        items: Vec<Data<'a, 'b>>,
+    },
+    /// A run of bits within an integer. The integer itself is a separate primative.
+    Bits {
+        span: Span,
+        identifier: Option<&'a str>,
+        /// Position of the lowest bit
+        offset: u32,
+        width: u32,
+        value: u128,
+    },
+}
+
+impl<'a, 'b> Data<'a, 'b> {
+    /// The range of input bytes covered by this node
+    pub fn span(&self) -> Span {
+        match self {
+            Data::Primative { span, .. }
+            | Data::List { span, .. }
+            | Data::Virtual { span, .. }
+            | Data::Padding { span, .. }
+            | Data::Slack { span, .. }
+            | Data::Decoded { span, .. }
+            | Data::Bits { span, .. } => *span,
+        }
+    }
+
+    pub fn identifier(&self) -> Option<&'a str> {
+        match self {
+            Data::Primative { identifier, .. }
+            | Data::List { identifier, .. }
+            | Data::Decoded { identifier, .. }
+            | Data::Bits { identifier, .. } => *identifier,
+            Data::Virtual { identifier, .. } => Some(identifier),
+            Data::Padding { .. } | Data::Slack { .. } => None,
+        }
+    }
+}
+
+/// Error for a read past the end of a reader, which blocks over a region of the input can
+/// recover from
+#[derive(Debug)]
+struct Overrun {
+    /// End of the reader which was overrun
+    end: usize,
+    message: String,
+}
+
+impl std::fmt::Display for Overrun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Overrun {}
+
+/// Cursor over the input bytes which keeps track of the current offset
+pub struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+    /// Number of bits already read from the byte at `pos`
+    bit: usize,
+    /// Offset reads are allowed up to, which is only before the end of the input inside a
+    /// LIMIT block
+    end: usize,
+}
+
+impl<'b> Reader<'b> {
+    pub fn new(bytes: &'b [u8]) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            bit: 0,
+            end: bytes.len(),
+        }
+    }
+
+    /// A reader over a range of the same input, which keeps offsets relative to the whole
+    /// input
+    fn region(&self, span: Span) -> Reader<'b> {
+        Reader {
+            bytes: self.bytes,
+            pos: span.start,
+            bit: 0,
+            end: span.end,
+        }
+    }
+
+    /// The bytes of a range which has already been read
+    fn covered(&self, span: Span) -> &'b [u8] {
+        &self.bytes[span.start..span.end]
+    }
+
+    /// Current offset from the start of the input
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// Current offset from the start of the input, in bits
+    fn bit_offset(&self) -> usize {
+        self.pos * 8 + self.bit
+    }
+
+    /// Number of bits which haven't been read yet
+    fn remaining_bits(&self) -> usize {
+        self.end * 8 - self.bit_offset()
+    }
+
+    /// Skip the rest of a partially read byte
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.pos += 1;
+            self.bit = 0;
+        }
+    }
+
+    /// Total size of the input
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Number of bytes which haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The bytes which haven't been read yet, without consuming them
+    fn rest(&self) -> &'b [u8] {
+        &self.bytes[self.pos..self.end]
+    }
+
+    /// The bytes read since the given offset
+    fn since(&self, start: usize) -> &'b [u8] {
+        &self.bytes[start..self.pos]
+    }
+
+    /// Take the next n bits, returning the bytes they touch and the range of bits within them
+    fn read_bits(&mut self, n: usize) -> Result<(&'b [u8], Span)> {
+        if n > self.remaining_bits() {
+            return Err(self.overrun(format!(
+                "Ran out of bits! Wanted {} at offset {}.{}, but only {} left",
+                n,
+                self.pos,
+                self.bit,
+                self.remaining_bits()
+            )));
+        }
+        let bits = Span {
+            start: self.bit,
+            end: self.bit + n,
+        };
+        let bytes = &self.bytes[self.pos..self.pos + bits.end.div_ceil(8)];
+        self.pos += bits.end / 8;
+        self.bit = bits.end % 8;
+
+        Ok((bytes, bits))
+    }
+
+    /// Take the next n bytes, starting from the next whole byte
+    fn read(&mut self, n: usize) -> Result<&'b [u8]> {
+        self.align();
+        if n > self.remaining() {
+            return Err(self.overrun(format!(
+                "Ran out of bytes! Wanted {} at offset {}, but only {} left",
+                n,
+                self.pos,
+                self.remaining()
+            )));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+
+        Ok(bytes)
+    }
+
+    fn overrun(&self, message: String) -> anyhow::Error {
+        Overrun {
+            end: self.end,
+            message,
+        }
+        .into()
+    }
+
+    /// Whether an error came from reading past the end of this reader
+    fn overran(&self, err: &anyhow::Error) -> bool {
+        err.downcast_ref::<Overrun>()
+            .is_some_and(|overrun| overrun.end == self.end)
+    }
+}
+
+/// Decode a single big/little endian unsigned integer of up to 16 bytes
+fn decode_uint(bytes: &[u8], endianness: Endianness) -> u128 {
+    let fold = |acc: u128, x: &u8| (acc << 8) | *x as u128;
+    match endianness {
+        Endianness::Big => bytes.iter().fold(0, fold),
+        Endianness::Little => bytes.iter().rev().fold(0, fold),
+    }
+}
+
+/// A single decoded character along with the bytes it came from, or bytes which aren't
+/// valid in the encoding
+#[derive(Debug, Clone, Copy)]
+pub enum TextUnit<'b> {
+    Char(char, &'b [u8]),
+    Invalid(&'b [u8]),
+}
+
+/// Decode text, keeping track of which bytes each character came from
+pub fn decode_text(bytes: &[u8], encoding: Encoding) -> Vec<TextUnit<'_>> {
+    match encoding {
+        Encoding::Ascii => bytes
+            .chunks(1)
+            .map(|b| match b[0] {
+                x if x.is_ascii() => TextUnit::Char(x as char, b),
+                _ => TextUnit::Invalid(b),
+            })
+            .collect(),
+        Encoding::Latin1 => bytes
+            .chunks(1)
+            .map(|b| TextUnit::Char(b[0] as char, b))
+            .collect(),
+        Encoding::Utf8 => {
+            let mut units = vec![];
+            let mut pos = 0;
+            for chunk in bytes.utf8_chunks() {
+                for c in chunk.valid().chars() {
+                    units.push(TextUnit::Char(c, &bytes[pos..pos + c.len_utf8()]));
+                    pos += c.len_utf8();
+                }
+                let invalid = chunk.invalid();
+                if !invalid.is_empty() {
+                    units.push(TextUnit::Invalid(&bytes[pos..pos + invalid.len()]));
+                    pos += invalid.len();
+                }
+            }
+
+            units
+        }
+        Encoding::Utf16(endianness) => {
+            let code_units = bytes
+                .chunks_exact(2)
+                .map(|b| decode_uint(b, endianness) as u16);
+
+            let mut units = vec![];
+            let mut pos = 0;
+            for c in char::decode_utf16(code_units) {
+                let len = c.as_ref().map_or(2, |c| c.len_utf16() * 2);
+                let b = &bytes[pos..pos + len];
+                units.push(match c {
+                    Ok(c) => TextUnit::Char(c, b),
+                    // Unpaired surrogate
+                    Err(_) => TextUnit::Invalid(b),
+                });
+                pos += len;
+            }
+            // Odd byte left over
+            if pos < bytes.len() {
+                units.push(TextUnit::Invalid(&bytes[pos..]));
+            }
+
+            units
+        }
+    }
 }
 
-impl PrimativeArray {
-    fn from_chunked_array(chunks: &[&[u8]], dtype: &DType) -> Self {
+/// Find the byte offset of the first NUL code unit
+fn find_nul(bytes: &[u8], encoding: Encoding) -> Option<usize> {
+    let size = encoding.unit_size();
+    bytes
+        .chunks_exact(size)
+        .position(|unit| unit.iter().all(|&x| x == 0))
+        .map(|i| i * size)
+}
+
+/// Decode an unsigned integer of up to 128 bits from a run of bits, reading either the most
+/// (big) or least (little) significant bit of each byte first
+fn decode_bits(bytes: &[u8], bits: Span, order: Endianness) -> u128 {
+    let bit = |i: usize| {
+        let shift = match order {
+            Endianness::Big => 7 - i % 8,
+            Endianness::Little => i % 8,
+        };
+        ((bytes[i / 8] >> shift) & 1) as u128
+    };
+    let fold = |acc: u128, i: usize| (acc << 1) | bit(i);
+    match order {
+        Endianness::Big => (bits.start..bits.end).fold(0, fold),
+        Endianness::Little => (bits.start..bits.end).rev().fold(0, fold),
+    }
+}
+
+/// Decode a single variable length integer, including its final byte
+fn decode_varint(bytes: &[u8], kind: Varint) -> u128 {
+    let groups = bytes.iter().map(|x| (x & 0x7f) as u128);
+    let leb = || {
+        groups.clone().enumerate().fold(0, |acc, (i, x)| {
+            acc | x.checked_shl(7 * i as u32).unwrap_or(0)
+        })
+    };
+    match kind {
+        Varint::Uleb128 => leb(),
+        Varint::Sleb128 => {
+            let bits = 7 * bytes.len() as u32;
+            let val = leb();
+            // Sign extend from the top bit of the last group
+            match bytes.last() {
+                Some(x) if x & 0x40 != 0 && bits < 128 => val | (u128::MAX << bits),
+                _ => val,
+            }
+        }
+        Varint::Vlq => groups.fold(0, |acc, x| (acc << 7) | x),
+        Varint::ZigZag => {
+            let val = leb();
+            ((val >> 1) as i128 ^ -((val & 1) as i128)) as u128
+        }
+    }
+}
+
+impl<'b> PrimativeArray<'b> {
+    fn new(bytes: &'b [u8], dtype: &DType) -> Self {
         use PrimativeArray::*;
         match dtype {
-            DType::U8 => U8(chunks
-                .iter()
-                .map(|x| {
-                    assert_eq!(x.len(), 1);
-                    x[0]
-                })
-                .collect()),
-            DType::U16(e) => U16(chunks
-                .iter()
-                .map(|&x| {
-                    assert_eq!(x.len(), 2);
-                    match e {
-                        Endianness::Big => u16::from_be_bytes(x.try_into().unwrap()),
-                        Endianness::Little => u16::from_le_bytes(x.try_into().unwrap()),
-                    }
-                })
-                .collect()),
-            DType::U32(e) => U32(chunks
-                .iter()
-                .map(|&x| {
-                    assert_eq!(x.len(), 4);
-                    match e {
-                        Endianness::Big => u32::from_be_bytes(x.try_into().unwrap()),
-                        Endianness::Little => u32::from_le_bytes(x.try_into().unwrap()),
-                    }
-                })
-                .collect()),
-            DType::U64(e) => U64(chunks
-                .iter()
-                .map(|&x| {
-                    assert_eq!(x.len(), 8);
-                    match e {
-                        Endianness::Big => u64::from_be_bytes(x.try_into().unwrap()),
-                        Endianness::Little => u64::from_le_bytes(x.try_into().unwrap()),
-                    }
-                })
-                .collect()),
-            DType::U128(e) => U128(
-                chunks
-                    .iter()
-                    .map(|&x| {
-                        assert_eq!(x.len(), 16);
-                        match e {
-                            Endianness::Big => u128::from_be_bytes(x.try_into().unwrap()),
-                            Endianness::Little => u128::from_le_bytes(x.try_into().unwrap()),
-                        }
-                    })
-                    .collect(),
-            ),
-            DType::Char => Char(
-                chunks
-                    .iter()
-                    .map(|x| {
-                        assert_eq!(x.len(), 1);
-                        x[0]
-                    })
-                    .collect(),
+            DType::U8 => U8(bytes),
+            DType::U16(e) => U16(bytes, *e),
+            DType::U32(e) => U32(bytes, *e),
+            DType::U64(e) => U64(bytes, *e),
+            DType::U128(e) => U128(bytes, *e),
+            DType::Char => Char(bytes),
+            DType::Text(encoding) => Str(
+                bytes,
+                Span {
+                    start: 0,
+                    end: bytes.len(),
+                },
+                *encoding,
             ),
+            DType::PaddedString(encoding) => {
+                let len = find_nul(bytes, *encoding).unwrap_or(bytes.len());
+                Str(bytes, Span { start: 0, end: len }, *encoding)
+            }
+            DType::BitInt(..) | DType::CString(_) | DType::PString(..) => {
+                unreachable!("Bit integers and variable length strings are read separately")
+            }
+            DType::Varint(kind) => Varint(bytes, *kind),
+            DType::Named(_) => unreachable!("Declared types are resolved before reading"),
+        }
+    }
+
+    /// The raw bytes backing the array
+    pub fn bytes(&self) -> &'b [u8] {
+        use PrimativeArray::*;
+        match self {
+            U8(b) | Char(b) | Str(b, ..) | UBits(b, ..) | Varint(b, _) => b,
+            U16(b, _) | U32(b, _) | U64(b, _) | U128(b, _) => b,
+        }
+    }
+
+    /// The text of a char array or string
+    pub fn text(&self) -> Option<&'b [u8]> {
+        match self {
+            PrimativeArray::Char(b) => Some(b),
+            PrimativeArray::Str(b, text, _) => Some(&b[text.start..text.end]),
+            _ => None,
+        }
+    }
+
+    /// The decoded text of a string, with invalid sequences replaced
+    pub fn string(&self) -> Option<String> {
+        let PrimativeArray::Str(_, _, encoding) = self else {
+            return None;
+        };
+        let string = decode_text(self.text()?, *encoding)
+            .into_iter()
+            .map(|unit| match unit {
+                TextUnit::Char(c, _) => c,
+                TextUnit::Invalid(_) => char::REPLACEMENT_CHARACTER,
+            })
+            .collect();
+
+        Some(string)
+    }
+
+    /// Number of bytes taken by each item
+    pub fn item_size(&self) -> usize {
+        use PrimativeArray::*;
+        match self {
+            U8(_) | Char(_) => 1,
+            U16(..) => 2,
+            U32(..) => 4,
+            U64(..) => 8,
+            U128(..) => 16,
+            Str(b, ..) => b.len(),
+            UBits(_, _, width, _) => (*width as usize).div_ceil(8),
+            // Items vary in size
+            Varint(..) => 1,
+        }
+    }
+
+    /// Whether the items are signed integers
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            PrimativeArray::Varint(_, Varint::Sleb128 | Varint::ZigZag)
+        )
+    }
+
+    /// Format an item of the array, taking its sign into account
+    pub fn format_int(&self, val: u128) -> String {
+        if self.is_signed() {
+            (val as i128).to_string()
+        } else {
+            val.to_string()
+        }
+    }
+
+    /// The bytes of each variable length integer
+    pub fn varints(&self) -> impl Iterator<Item = &'b [u8]> + use<'b> {
+        self.bytes().split_inclusive(|x| x & 0x80 == 0)
+    }
+
+    /// Number of items in the array, a string being a single item
+    pub fn len(&self) -> usize {
+        match self {
+            PrimativeArray::Str(..) => 1,
+            PrimativeArray::UBits(_, bits, width, _) => bits.len() / *width as usize,
+            PrimativeArray::Varint(..) => self.varints().count(),
+            _ => self.bytes().len() / self.item_size(),
+        }
+    }
+
+    /// Decode the item at the given index
+    pub fn get(&self, index: usize) -> Option<u128> {
+        use PrimativeArray::*;
+        match self {
+            Str(..) => return None,
+            UBits(b, bits, width, order) => {
+                if index >= self.len() {
+                    return None;
+                }
+                let start = bits.start + index * *width as usize;
+                let end = start + *width as usize;
+                return Some(decode_bits(b, Span { start, end }, *order));
+            }
+            Varint(_, kind) => return Some(decode_varint(self.varints().nth(index)?, *kind)),
+            _ => {}
+        }
+
+        let size = self.item_size();
+        let bytes = self.bytes().get(index * size..(index + 1) * size)?;
+        let val = match self {
+            U16(_, e) | U32(_, e) | U64(_, e) | U128(_, e) => decode_uint(bytes, *e),
+            _ => bytes[0] as u128,
+        };
+
+        Some(val)
+    }
+
+    /// A single item of the array, still backed by the input, along with the bytes it
+    /// covers given the bytes the whole array covers
+    fn item(&self, span: Span, index: usize) -> (Span, Self) {
+        use PrimativeArray::*;
+        match self {
+            Str(..) => return (span, *self),
+            UBits(b, bits, width, order) => {
+                let start = bits.start + index * *width as usize;
+                let end = start + *width as usize;
+                // Keep the bits relative to the first byte the item touches
+                let skip = start / 8;
+                let item_span = Span {
+                    start: span.start + skip,
+                    end: span.start + end.div_ceil(8),
+                };
+                let bits = Span {
+                    start: start - skip * 8,
+                    end: end - skip * 8,
+                };
+                return (
+                    item_span,
+                    UBits(&b[skip..end.div_ceil(8)], bits, *width, *order),
+                );
+            }
+            Varint(_, kind) => {
+                let skip = self.varints().take(index).map(|x| x.len()).sum::<usize>();
+                let bytes = self.varints().nth(index).unwrap();
+                let item_span = Span {
+                    start: span.start + skip,
+                    end: span.start + skip + bytes.len(),
+                };
+                return (item_span, Varint(bytes, *kind));
+            }
+            _ => {}
+        }
+
+        let size = self.item_size();
+        let bytes = &self.bytes()[index * size..(index + 1) * size];
+        let item_span = Span {
+            start: span.start + index * size,
+            end: span.start + (index + 1) * size,
+        };
+        let item = match self {
+            U8(_) => U8(bytes),
+            Char(_) => Char(bytes),
+            U16(_, e) => U16(bytes, *e),
+            U32(_, e) => U32(bytes, *e),
+            U64(_, e) => U64(bytes, *e),
+            U128(_, e) => U128(bytes, *e),
+            _ => unreachable!(),
+        };
+
+        (item_span, item)
+    }
+
+    /// Decode every item in the array. Strings don't have any integer items.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = u128> + 'b> {
+        let array = *self;
+        match array {
+            // Avoid rescanning for the start of every item
+            PrimativeArray::Varint(_, kind) => {
+                Box::new(array.varints().map(move |x| decode_varint(x, kind)))
+            }
+            _ => Box::new((0..array.len()).map_while(move |i| array.get(i))),
         }
     }
 }
 
-pub struct Stack<'a> {
-    variables: Vec<HashMap<&'a str, PrimativeArray>>,
+/// Upper bounds on the work done by the interpreter, so that corrupt or hostile inputs
+/// fail cleanly instead of exhausting memory or hanging
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum number of bytes a single primative can take
+    pub max_alloc: usize,
+    /// Maximum number of loop iterations over the whole run
+    pub max_iterations: usize,
+    /// Maximum nesting depth of pattern blocks
+    pub max_depth: usize,
 }
 
-impl<'a> Stack<'a> {
-    pub fn new() -> Self {
-        Self { variables: vec![] }
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_alloc: 1 << 30,
+            max_iterations: 10_000_000,
+            max_depth: 64,
+        }
+    }
+}
+
+pub struct Stack<'a, 'b> {
+    variables: Vec<Record<'a, 'b>>,
+    /// Names in each layer which were bound by LET, so later LETs can update them
+    lets: Vec<HashSet<&'a str>>,
+    limits: Limits,
+    iterations: usize,
+    depth: usize,
+    /// Whether failed checks are recorded as warnings instead of stopping the parse
+    lenient: bool,
+    warnings: Vec<String>,
+    /// Types declared with ENUM or FLAGS, which are available to everything after them
+    enums: HashMap<&'a str, &'a EnumDecl>,
+    /// Patterns declared with STRUCT
+    structs: HashMap<&'a str, &'a [Expr]>,
+    buffers: &'b Buffers,
+}
+
+impl<'a, 'b> Stack<'a, 'b> {
+    /// Create a stack which keeps any bytes it makes in `buffers`
+    pub fn new(buffers: &'b Buffers) -> Self {
+        Self {
+            variables: vec![],
+            lets: vec![],
+            limits: Limits::default(),
+            iterations: 0,
+            depth: 0,
+            lenient: false,
+            warnings: vec![],
+            enums: HashMap::new(),
+            structs: HashMap::new(),
+            buffers,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_lenient_checks(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Problems found in lenient mode which didn't stop the parse
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Report a failed check, either as an error or a warning
+    fn fail_check(&mut self, message: String) -> Result<()> {
+        if !self.lenient {
+            bail!(message);
+        }
+        self.warnings.push(message);
+
+        Ok(())
+    }
+
+    /// Count a loop iteration against the iteration limit
+    fn tick(&mut self) -> Result<()> {
+        self.iterations += 1;
+        if self.iterations > self.limits.max_iterations {
+            bail!(
+                "Exceeded the maximum number of iterations ({})",
+                self.limits.max_iterations
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check that a run of bytes is within the allocation limit
+    fn check_alloc(&self, count: usize, bytes_per_data: usize) -> Result<usize> {
+        let size = count.checked_mul(bytes_per_data).with_context(|| {
+            format!("Primative size overflowed: {} x {}", count, bytes_per_data)
+        })?;
+        if size > self.limits.max_alloc {
+            bail!(
+                "Primative of {} bytes exceeds the allocation limit ({})",
+                size,
+                self.limits.max_alloc
+            );
+        }
+
+        Ok(size)
+    }
+
+    fn get_enum(&self, name: &str) -> Result<&'a EnumDecl> {
+        self.enums
+            .get(name)
+            .copied()
+            .with_context(|| format!("Type not found: {:?}", name))
+    }
+
+    fn get_struct(&self, name: &str) -> Result<&'a [Expr]> {
+        self.structs
+            .get(name)
+            .copied()
+            .with_context(|| format!("Struct not found: {:?}", name))
     }
 
     /// Add a new layer to the stack
     fn add_layer(&mut self) {
         self.variables.push(HashMap::new());
+        self.lets.push(HashSet::new());
     }
 
-    /// Remove the last layer of the stack
-    fn remove_layer(&mut self) {
-        assert!(!self.variables.is_empty(), "Stack is empty!");
-        self.variables.pop();
+    /// Remove the last layer of the stack, returning the variables set in it
+    fn remove_layer(&mut self) -> Record<'a, 'b> {
+        self.lets.pop();
+        self.variables.pop().expect("Stack is empty!")
+    }
+
+    /// Remove layers until there are only the given number left
+    fn truncate_layers(&mut self, layers: usize) {
+        self.variables.truncate(layers);
+        self.lets.truncate(layers);
     }
 
     /// Search up the stack for the given variable
-    fn get_var(&self, key: &str) -> Option<&PrimativeArray> {
+    fn get_var(&self, key: &str) -> Option<&Value<'a, 'b>> {
         self.variables.iter().rev().find_map(|vars| vars.get(key))
     }
 
+    /// Update the nearest variable with the given name if it was bound by an earlier LET, or
+    /// bind it at the current layer of the stack otherwise, so parsed fields are never changed
+    fn assign_var(&mut self, key: &'a str, val: Value<'a, 'b>) {
+        let layer = self
+            .variables
+            .iter()
+            .rposition(|vars| vars.contains_key(key));
+        match layer {
+            Some(layer) if self.lets[layer].contains(key) => {
+                self.variables[layer].insert(key, val);
+            }
+            _ => {
+                self.set_var(key, val);
+                self.lets.last_mut().expect("Stack is empty!").insert(key);
+            }
+        }
+    }
+
     /// Set the variable value at the current layer of the stack
-    fn set_var(&mut self, key: &'a str, val: PrimativeArray) {
+    fn set_var(&mut self, key: &'a str, val: Value<'a, 'b>) {
         self.variables
             .iter_mut()
             .last()
             .expect("Stack is empty!")
             .entry(key)
             .insert_entry(val);
+        self.lets.last_mut().expect("Stack is empty!").remove(key);
     }
 }
 
+/// Evaluate an expression against the stack
+fn eval<'a, 'b>(stack: &Stack<'a, 'b>, bytes: &Reader, expr: &ValueExpr) -> Result<Value<'a, 'b>> {
+    let val = match expr {
+        ValueExpr::Number(n) => Value::Int(*n as i128),
+        ValueExpr::Bytes(b) => Value::Bytes(b.as_slice().into()),
+        // Built-in variables
+        ValueExpr::Variable(id) if id == "OFFSET" => Value::Int(bytes.offset() as i128),
+        ValueExpr::Variable(id) if id == "FILE_SIZE" => Value::Int(bytes.len() as i128),
+        ValueExpr::Variable(id) if id == "REMAINING" => Value::Int(bytes.remaining() as i128),
+        ValueExpr::Variable(id) => stack
+            .get_var(id)
+            .with_context(|| format!("Variable not found: {:?}", id))?
+            .clone(),
+        ValueExpr::Negate(x) => Value::Int(
+            eval(stack, bytes, x)?
+                .as_int()?
+                .checked_neg()
+                .with_context(|| format!("Invalid arithmetic: {}", expr))?,
+        ),
+        ValueExpr::Index(array, index) => {
+            let array = eval(stack, bytes, array)?;
+            let len = match &array {
+                Value::Array(_, array) => array.len(),
+                Value::List(items) => items.len(),
+                _ => bail!("Cannot index into: {:?}", array),
+            } as i128;
+
+            // Negative indices count back from the end
+            let index = eval(stack, bytes, index)?.as_int()?;
+            let i = if index < 0 { len + index } else { index };
+            if !(0..len).contains(&i) {
+                bail!("Index {} out of bounds for length {}", index, len);
+            }
+
+            match array {
+                Value::Array(span, array) => {
+                    let (span, item) = array.item(span, i as usize);
+                    Value::Array(span, item)
+                }
+                Value::List(items) => items[i as usize].clone(),
+                _ => unreachable!(),
+            }
+        }
+        // Values of an ENUM or FLAGS, e.g. `TiffTag.ImageWidth`
+        ValueExpr::Field(base, field)
+            if let ValueExpr::Variable(name) = base.as_ref()
+                && let Some(decl) = stack.enums.get(name.as_str()) =>
+        {
+            let (_, value) = decl
+                .variants
+                .iter()
+                .find(|(variant, _)| variant == field)
+                .with_context(|| format!("{:?} has no value {:?}", name, field))?;
+
+            Value::Int(*value as i128)
+        }
+        ValueExpr::Field(record, field) => {
+            let record = match eval(stack, bytes, record)? {
+                // Lists are represented by their first item
+                Value::List(items) => items
+                    .first()
+                    .with_context(|| format!("Cannot access {:?} of an empty list", field))?
+                    .clone(),
+                record => record,
+            };
+            let Value::Record(.., record) = record else {
+                bail!("Cannot access {:?} of: {:?}", field, record);
+            };
+
+            record
+                .get(field.as_str())
+                .with_context(|| format!("Field not found: {:?}", field))?
+                .clone()
+        }
+        ValueExpr::Binary(op @ (BinOp::Eq | BinOp::Ne), lhs, rhs) => {
+            let equal = eval(stack, bytes, lhs)?.equals(&eval(stack, bytes, rhs)?)?;
+
+            Value::Int((equal == matches!(op, BinOp::Eq)) as i128)
+        }
+        ValueExpr::Binary(op, lhs, rhs) => {
+            let lhs = eval(stack, bytes, lhs)?.as_int()?;
+            let rhs = eval(stack, bytes, rhs)?.as_int()?;
+            let val = match op {
+                BinOp::Add => lhs.checked_add(rhs),
+                BinOp::Sub => lhs.checked_sub(rhs),
+                BinOp::Mul => lhs.checked_mul(rhs),
+                BinOp::Div => lhs.checked_div(rhs),
+                BinOp::Rem => lhs.checked_rem(rhs),
+                BinOp::Lt => Some((lhs < rhs) as i128),
+                BinOp::Le => Some((lhs <= rhs) as i128),
+                BinOp::Gt => Some((lhs > rhs) as i128),
+                BinOp::Ge => Some((lhs >= rhs) as i128),
+                BinOp::Eq | BinOp::Ne => unreachable!(),
+            };
+
+            Value::Int(val.with_context(|| format!("Invalid arithmetic: {}", expr))?)
+        }
+        ValueExpr::SizeOf(dtype) => Value::Int(
+            dtype
+                .size()
+                .with_context(|| format!("{:?} doesn't have a fixed size in bytes", dtype))?
+                as i128,
+        ),
+        // Declared types are sized by name, like built-in dtypes
+        ValueExpr::Call(name, args)
+            if name == "sizeof"
+                && let [ValueExpr::Variable(id)] = args.as_slice()
+                && let Some(decl) = stack.enums.get(id.as_str()) =>
+        {
+            let size = decl
+                .dtype
+                .size()
+                .with_context(|| format!("{} doesn't have a fixed size in bytes", id))?;
+
+            Value::Int(size as i128)
+        }
+        ValueExpr::Call(name, args)
+            if name == "sizeof"
+                && let [ValueExpr::Variable(id)] = args.as_slice()
+                && let Some(exprs) = stack.structs.get(id.as_str()) =>
+        {
+            let size = fixed_size(stack, exprs)
+                .with_context(|| format!("STRUCT {} doesn't have a fixed size in bytes", id))?;
+
+            Value::Int(size as i128)
+        }
+        ValueExpr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|x| eval(stack, bytes, x))
+                .collect::<Result<Vec<_>>>()?;
+
+            call_builtin(name, &args)?
+        }
+    };
+
+    Ok(val)
+}
+
+/// Number of bytes a pattern always takes, for patterns which only use fixed sizes and counts
+fn fixed_size(stack: &Stack, exprs: &[Expr]) -> Result<usize> {
+    let number = |value: &ValueExpr| match value {
+        ValueExpr::Number(n) => usize::try_from(*n).ok(),
+        _ => None,
+    };
+    let mut total: usize = 0;
+    for p in exprs {
+        let size = match p {
+            Expr::Primative {
+                dtype,
+                count: Count::Value(count),
+                ..
+            } => {
+                let dtype = match dtype {
+                    DType::Named(name) => &stack.get_enum(name)?.dtype,
+                    dtype => dtype,
+                };
+                dtype
+                    .size()
+                    .zip(number(count))
+                    .and_then(|(size, count)| size.checked_mul(count))
+            }
+            Expr::TakeN {
+                count: Count::Value(count),
+                exprs,
+                ..
+            } => match number(count) {
+                Some(count) => fixed_size(stack, exprs)?.checked_mul(count),
+                None => None,
+            },
+            Expr::Limit { size, .. } | Expr::Pad(size) => number(size),
+            Expr::Bits { dtype, .. } => dtype.size(),
+            // These don't read anything
+            Expr::Decode { .. }
+            | Expr::Let { .. }
+            | Expr::Assert(_)
+            | Expr::Enum(_)
+            | Expr::Struct { .. } => Some(0),
+            _ => None,
+        };
+        total = size
+            .and_then(|size| total.checked_add(size))
+            .with_context(|| format!("{} doesn't have a fixed size", describe(p)))?;
+    }
+
+    Ok(total)
+}
+
+/// Call one of the built-in functions
+fn call_builtin<'a, 'b>(name: &str, args: &[Value<'a, 'b>]) -> Result<Value<'a, 'b>> {
+    let val = match (name, args) {
+        // The length of a string is its length in code units, without any prefix or NUL
+        ("len", [Value::Array(_, PrimativeArray::Str(_, text, encoding))]) => {
+            text.len() / encoding.unit_size()
+        }
+        ("len", [Value::Array(_, array)]) => array.len(),
+        ("len", [Value::List(items)]) => items.len(),
+        ("len", [Value::Bytes(bytes)]) => bytes.len(),
+        ("sizeof", [x]) => x.size()?,
+        ("min" | "max", [_, ..]) => {
+            let args = args
+                .iter()
+                .map(|x| x.as_int())
+                .collect::<Result<Vec<_>>>()?;
+            let val = if name == "min" {
+                args.into_iter().min()
+            } else {
+                args.into_iter().max()
+            };
+
+            return Ok(Value::Int(val.unwrap()));
+        }
+        _ => bail!("Invalid call: {}({:?})", name, args),
+    };
+
+    Ok(Value::Int(val as i128))
+}
+
+/// Resolve a count to a number of items, or None if unbounded
+fn resolve_count(stack: &Stack, bytes: &Reader, count: &Count) -> Result<Option<usize>> {
+    let count = match count {
+        Count::Value(expr) => {
+            let val = eval(stack, bytes, expr)?.as_int()?;
+            let val =
+                usize::try_from(val).with_context(|| format!("Cannot use {} as a count", val))?;
+
+            Some(val)
+        }
+        Count::Infinite => None,
+    };
+
+    Ok(count)
+}
+
+/// Read a single variable length string
+fn read_string<'b>(
+    stack: &Stack,
+    bytes: &mut Reader<'b>,
+    dtype: &DType,
+) -> Result<PrimativeArray<'b>> {
+    let start = bytes.offset();
+    let (text, encoding) = match dtype {
+        DType::CString(encoding) => {
+            let len = find_nul(bytes.rest(), *encoding)
+                .with_context(|| format!("Unterminated cstring at offset {:#x}", start))?;
+            bytes.read(len + encoding.unit_size())?;
+
+            (Span { start: 0, end: len }, encoding)
+        }
+        DType::PString(width, endianness, encoding) => {
+            let len = decode_uint(bytes.read(*width)?, *endianness);
+            let len = usize::try_from(len)
+                .with_context(|| format!("Cannot use {} as a string length", len))?;
+            let size = stack.check_alloc(len, encoding.unit_size())?;
+            bytes.read(size)?;
+
+            let text = Span {
+                start: *width,
+                end: width + size,
+            };
+            (text, encoding)
+        }
+        _ => unreachable!("Not a variable length string: {:?}", dtype),
+    };
+
+    Ok(PrimativeArray::Str(bytes.since(start), text, *encoding))
+}
+
+/// Enough 7 bit groups for any 128 bit integer
+const MAX_VARINT_SIZE: usize = 19;
+
 /// Attempt to parse a primative from the byte stream
-fn process_primative<'a>(
-    stack: &mut Stack<'a>,
-    bytes: &mut impl Iterator<Item = u8>,
+fn process_primative<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &mut Reader<'b>,
     dtype: &DType,
     count: &Count,
     identifier: &'a Option<String>,
-) -> Result<Data> {
-    let count = match count {
-        Count::Number(n) => Some(*n as usize),
-        Count::Identifier(id) => {
-            // Search up the scope stack
-            let val = stack
-                .get_var(id)
-                .with_context(|| format!("Variable not found: {:?}", id))?;
-
-            let val = match val {
-                PrimativeArray::U8(items) => items[0] as usize,
-                PrimativeArray::U16(items) => items[0] as usize,
-                PrimativeArray::U32(items) => items[0] as usize,
-                PrimativeArray::U64(items) => items[0] as usize,
-                PrimativeArray::U128(_) => bail!("Cannot downcast u128 -> usize"),
-                _ => bail!("Cannot use dtype as count: {:?}", val),
+    expect: &Option<ValueExpr>,
+    parse_as: &Option<String>,
+) -> Result<Data<'a, 'b>> {
+    let count = resolve_count(stack, bytes, count)?;
+
+    let (dtype, names) = match dtype {
+        DType::Named(name) => {
+            let decl = stack.get_enum(name)?;
+            (&decl.dtype, Some(decl))
+        }
+        dtype => (dtype, None),
+    };
+
+    // Only bit integers can start part way through a byte
+    if !matches!(dtype, DType::BitInt(..)) {
+        bytes.align();
+    }
+    let start = bytes.offset();
+    let (mut value, mut data) = match (dtype, dtype.size()) {
+        (DType::BitInt(width, order), _) => {
+            let width = *width as usize;
+            // Unbounded N takes as many whole items as are left
+            let count = count.unwrap_or(bytes.remaining_bits() / width);
+
+            let bits = count.checked_mul(width).with_context(|| {
+                format!("Primative size overflowed: {} x {} bits", count, width)
+            })?;
+            stack.check_alloc(bits.div_ceil(8), 1)?;
+
+            let (data, bits) = bytes.read_bits(bits)?;
+            let span = Span {
+                start,
+                end: start + data.len(),
+            };
+            let primative = PrimativeArray::UBits(data, bits, width as u32, *order);
+            let data = Data::Primative {
+                span,
+                identifier: identifier.as_deref(),
+                array: primative,
+                names,
+                checksum: None,
             };
 
-            Some(val)
+            (Value::Array(span, primative), data)
+        }
+        (DType::Varint(kind), _) => {
+            let mut n = 0;
+            while count.map_or(!bytes.is_empty(), |count| n < count) {
+                stack.tick()?;
+
+                let item_start = bytes.offset();
+                let len = bytes
+                    .rest()
+                    .iter()
+                    .position(|x| x & 0x80 == 0)
+                    .with_context(|| format!("Unterminated varint at offset {:#x}", item_start))?;
+                if len >= MAX_VARINT_SIZE {
+                    bail!("Varint at offset {:#x} is too long", item_start);
+                }
+                bytes.read(len + 1)?;
+                n += 1;
+            }
+
+            let span = Span {
+                start,
+                end: bytes.offset(),
+            };
+            let primative = PrimativeArray::Varint(bytes.since(start), *kind);
+            let data = Data::Primative {
+                span,
+                identifier: identifier.as_deref(),
+                array: primative,
+                names,
+                checksum: None,
+            };
+
+            (Value::Array(span, primative), data)
+        }
+        (_, Some(bytes_per_data)) => {
+            // Unbounded N takes as many whole items as are left
+            let count = count.unwrap_or(bytes.remaining() / bytes_per_data);
+
+            let size = stack.check_alloc(count, bytes_per_data)?;
+
+            let data = bytes.read(size)?;
+            let span = Span {
+                start,
+                end: bytes.offset(),
+            };
+
+            let primative = PrimativeArray::new(data, dtype);
+            let data = Data::Primative {
+                span,
+                identifier: identifier.as_deref(),
+                array: primative,
+                names,
+                checksum: None,
+            };
+
+            (Value::Array(span, primative), data)
+        }
+        (_, None) if count == Some(1) => {
+            let string = read_string(stack, bytes, dtype)?;
+            let span = Span {
+                start,
+                end: bytes.offset(),
+            };
+            let data = Data::Primative {
+                span,
+                identifier: identifier.as_deref(),
+                array: string,
+                names: None,
+                checksum: None,
+            };
+
+            (Value::Array(span, string), data)
+        }
+        // Runs of variable length strings get a node each
+        (_, None) => {
+            let mut strings = vec![];
+            let mut items = vec![];
+            while count.map_or(!bytes.is_empty(), |n| strings.len() < n) {
+                stack.tick()?;
+
+                let start = bytes.offset();
+                let string = read_string(stack, bytes, dtype)
+                    .with_context(|| format!("Failed to read string #{}", strings.len()))?;
+                let span = Span {
+                    start,
+                    end: bytes.offset(),
+                };
+                strings.push(Value::Array(span, string));
+                items.push(Data::Primative {
+                    span,
+                    identifier: None,
+                    array: string,
+                    names: None,
+                    checksum: None,
+                });
+            }
+
+            let data = Data::List {
+                span: Span {
+                    start,
+                    end: bytes.offset(),
+                },
+                identifier: identifier.as_deref(),
+                items,
+            };
+
+            (Value::List(Rc::new(strings)), data)
         }
-        Count::Infinite => None,
     };
 
-    let bytes_per_data = match dtype {
-        DType::U8 => 1,
-        DType::U16(_) => 2,
-        DType::U32(_) => 4,
-        DType::U64(_) => 8,
-        DType::U128(_) => 16,
-        DType::Char => 1,
+    if let Some(expect) = expect {
+        let expected = eval(stack, bytes, expect)?;
+        if !value.equals(&expected)? {
+            stack.fail_check(format!(
+                "EXPECT failed at offset {:#x}: expected {}, found {}",
+                start,
+                expected.describe(),
+                value.describe()
+            ))?;
+        }
+    }
+
+    // The field's bytes are replaced by the fields the struct finds in them
+    if let Some(name) = parse_as {
+        let exprs = stack.get_struct(name)?;
+        let span = data.span();
+        if let Data::List { .. } = data {
+            bail!("Only a single field can be parsed AS {}, not a list", name);
+        }
+
+        let (items, record) =
+            process_region(stack, bytes.region(span), exprs, name).with_context(|| {
+                format!(
+                    "Failed to parse {:#x}..{:#x} AS {}",
+                    span.start, span.end, name
+                )
+            })?;
+        value = Value::Record(span, bytes.covered(span), Rc::new(record));
+        data = Data::List {
+            span,
+            identifier: identifier.as_deref(),
+            items,
+        };
+    }
+
+    if let Some(id) = identifier {
+        stack.set_var(id, value);
     };
 
-    let data = if let Some(count) = count {
-        // Bounded N
-        (0..count * bytes_per_data)
-            .map(|_| bytes.next().context("Ran out of bytes!"))
-            .collect::<Result<Vec<_>>>()?
+    Ok(data)
+}
+
+/// Check a field against the checksum of the bytes of other fields. A mismatch is only a
+/// warning, so the rest of the file can still be inspected.
+fn process_checksum<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &Reader<'b>,
+    data: &mut Data<'a, 'b>,
+    algorithm: Checksum,
+    over: &[ValueExpr],
+) -> Result<()> {
+    let Data::Primative {
+        span,
+        array,
+        checksum,
+        ..
+    } = data
+    else {
+        bail!("Only a single field can be a CHECKSUM");
+    };
+
+    let mut covered = vec![];
+    for field in over {
+        let value = eval(stack, bytes, field)?;
+        // Each pass through a named block is covered in turn
+        let values = match value {
+            Value::List(items) => items.to_vec(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Array(_, array) => covered.extend_from_slice(array.bytes()),
+                Value::Bytes(literal) => covered.extend_from_slice(&literal),
+                Value::Record(_, block, _) => covered.extend_from_slice(block),
+                val => bail!("Cannot take the checksum of {}", val.describe()),
+            }
+        }
+    }
+
+    let computed = checksum::compute(algorithm, &covered);
+    let valid = if algorithm.is_int() {
+        array.len() == 1 && array.get(0) == Some(decode_uint(&computed, Endianness::Big))
     } else {
-        // Unbounded N
-        bytes.collect()
+        array.bytes() == computed
+    };
+    let check = ChecksumCheck {
+        algorithm,
+        computed,
+        valid,
+    };
+    if !valid {
+        stack.warnings.push(format!(
+            "{} CHECKSUM at offset {:#x} doesn't match, expected {}",
+            algorithm.name(),
+            span.start,
+            check.computed_hex()
+        ));
+    }
+    *checksum = Some(check);
+
+    Ok(())
+}
+
+/// Bind a derived value to a variable, without consuming any bytes
+fn process_let<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &Reader<'b>,
+    identifier: &'a str,
+    value: &ValueExpr,
+) -> Result<Data<'a, 'b>> {
+    let value = eval(stack, bytes, value)?;
+    stack.assign_var(identifier, value.clone());
+
+    Ok(Data::Virtual {
+        span: Span {
+            start: bytes.offset(),
+            end: bytes.offset(),
+        },
+        identifier,
+        value,
+    })
+}
+
+/// Check that a condition holds, showing both sides of a failed comparison
+fn process_assert(stack: &mut Stack, bytes: &Reader, condition: &ValueExpr) -> Result<()> {
+    if eval(stack, bytes, condition)?.as_int()? != 0 {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "ASSERT failed at offset {:#x}: {}",
+        bytes.offset(),
+        condition
+    );
+    if let ValueExpr::Binary(_, lhs, rhs) = condition {
+        message += &format!(
+            " (left: {}, right: {})",
+            eval(stack, bytes, lhs)?.describe(),
+            eval(stack, bytes, rhs)?.describe()
+        );
+    }
+
+    stack.fail_check(message)
+}
+
+/// Read an integer and split it into runs of bits, starting from the least significant bit
+fn process_bits<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &mut Reader<'b>,
+    dtype: &DType,
+    identifier: &'a Option<String>,
+    fields: &'a [BitField],
+) -> Result<Data<'a, 'b>> {
+    let size = match dtype {
+        DType::U8 | DType::U16(_) | DType::U32(_) | DType::U64(_) | DType::U128(_) => {
+            dtype.size().unwrap()
+        }
+        _ => bail!("BITS needs an integer dtype, not {:?}", dtype),
+    };
+    let total = fields
+        .iter()
+        .try_fold(0u64, |total, f| total.checked_add(f.width));
+    match total {
+        Some(total) if total <= size as u64 * 8 => {}
+        Some(total) => bail!(
+            "Bit fields take {} bits, but {:?} only has {}",
+            total,
+            dtype,
+            size * 8
+        ),
+        None => bail!("Bit fields take more bits than {:?} has", dtype),
+    }
+
+    bytes.align();
+    let start = bytes.offset();
+    let array = PrimativeArray::new(bytes.read(size)?, dtype);
+    let span = Span {
+        start,
+        end: bytes.offset(),
     };
-    let data = data.chunks_exact(bytes_per_data).collect::<Vec<_>>();
-    let primative = PrimativeArray::from_chunked_array(&data, dtype);
+    let word = array.get(0).unwrap();
+
+    let mut items = vec![Data::Primative {
+        span,
+        identifier: None,
+        array,
+        names: None,
+        checksum: None,
+    }];
+    let mut record = Record::new();
+    let mut offset = 0;
+    for field in fields {
+        let width = field.width as u32;
+        let mask = u128::MAX.checked_shr(128 - width).unwrap_or(0);
+        let value = word.checked_shr(offset).unwrap_or(0) & mask;
+
+        if let Some(id) = &field.identifier {
+            let val = i128::try_from(value)
+                .with_context(|| format!("Cannot downcast {} -> i128", value))?;
+            stack.set_var(id, Value::Int(val));
+            record.insert(id, Value::Int(val));
+        }
+        items.push(Data::Bits {
+            span,
+            identifier: field.identifier.as_deref(),
+            offset,
+            width,
+            value,
+        });
+
+        offset += width;
+    }
 
     if let Some(id) = identifier {
-        stack.set_var(id, primative.clone());
+        stack.set_var(
+            id,
+            Value::Record(span, bytes.covered(span), Rc::new(record)),
+        );
+    }
+
+    Ok(Data::List {
+        span,
+        identifier: identifier.as_deref(),
+        items,
+    })
+}
+
+/// Skip some number of bytes, or to a multiple of some number of bytes from `base`. Nothing is
+/// added to the tree if there's nothing to skip.
+fn process_padding<'b>(
+    stack: &Stack,
+    bytes: &mut Reader<'b>,
+    size: &ValueExpr,
+    align_from: Option<usize>,
+) -> Result<Option<Data<'static, 'b>>> {
+    bytes.align();
+    let size = eval(stack, bytes, size)?.as_int()?;
+    let size = usize::try_from(size).with_context(|| format!("Cannot use {} as a size", size))?;
+    let size = match align_from {
+        Some(_) if size == 0 => bail!("Cannot align to a multiple of 0 bytes"),
+        Some(base) => (size - (bytes.offset() - base) % size) % size,
+        None => size,
     };
+    if size == 0 {
+        return Ok(None);
+    }
+    stack.check_alloc(size, 1)?;
 
-    Ok(Data::Primative(primative))
+    let start = bytes.offset();
+    let padding = bytes.read(size)?;
+
+    Ok(Some(Data::Padding {
+        span: Span {
+            start,
+            end: bytes.offset(),
+        },
+        bytes: padding,
+    }))
+}
+
+/// Bind the records from each pass through a named block to its identifier
+fn bind_records<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &Reader<'b>,
+    identifier: &'a Option<String>,
+    records: Vec<(Span, Record<'a, 'b>)>,
+) {
+    if let Some(id) = identifier {
+        let records = records
+            .into_iter()
+            .map(|(span, r)| Value::Record(span, bytes.covered(span), Rc::new(r)));
+        stack.set_var(id, Value::List(Rc::new(records.collect())));
+    }
 }
 
 /// Take a pattern N times in a row
-fn process_take_n<'a>(
-    stack: &mut Stack<'a>,
-    bytes: &mut Peekable<impl Iterator<Item = u8>>,
+fn process_take_n<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &mut Reader<'b>,
     count: &Count,
+    identifier: &'a Option<String>,
     exprs: &'a [Expr],
-) -> Result<Data> {
-    let count = match count {
-        Count::Number(n) => Some(*n as usize),
-        Count::Identifier(id) => {
-            // Search up the scope stack
-            let val = stack
-                .get_var(id)
-                .with_context(|| format!("Variable not found: {:?}", id))?;
-
-            let val = match val {
-                PrimativeArray::U8(items) => items[0] as usize,
-                PrimativeArray::U16(items) => items[0] as usize,
-                PrimativeArray::U32(items) => items[0] as usize,
-                PrimativeArray::U64(items) => items[0] as usize,
-                PrimativeArray::U128(_) => bail!("Cannot downcast u128 -> usize"),
-                _ => bail!("Cannot use dtype as count: {:?}", val),
-            };
+) -> Result<Data<'a, 'b>> {
+    let count = resolve_count(stack, bytes, count)?;
 
-            Some(val)
-        }
-        Count::Infinite => None,
-    };
+    let start = bytes.offset();
 
-    let sub_parsed = if let Some(count) = count {
+    let mut sub_parsed = vec![];
+    let mut records = vec![];
+    if let Some(count) = count {
         // Bounded N
-        (0..count)
-            .map(|i| {
-                process_bytes(exprs, bytes, stack)
-                    .with_context(|| format!("Failed to parse TAKE_N item #{}", i))
-            })
-            .collect::<Result<Vec<_>>>()?
+        for i in 0..count {
+            stack.tick()?;
+            let (data, record) = process_scope(exprs, bytes, stack)
+                .with_context(|| format!("Failed to parse TAKE_N item #{}", i))?;
+            // Records are only kept if they can be referenced later
+            if identifier.is_some() {
+                records.push((data.span(), record));
+            }
+            sub_parsed.push(data);
+        }
     } else {
         // Unbounded N
-        let mut sub_parsed = vec![];
-        while bytes.peek().is_some() {
-            sub_parsed.push(process_bytes(exprs, bytes, stack)?);
+        while !bytes.is_empty() {
+            stack.tick()?;
+
+            let start = bytes.bit_offset();
+            let (data, record) = process_scope(exprs, bytes, stack)?;
+            if identifier.is_some() {
+                records.push((data.span(), record));
+            }
+            sub_parsed.push(data);
+
+            // A body which doesn't consume anything would loop forever
+            if bytes.bit_offset() == start {
+                bail!(
+                    "TAKE_N * body consumed no bytes at offset {}, aborting infinite loop",
+                    bytes.offset()
+                );
+            }
+        }
+    }
+
+    bind_records(stack, bytes, identifier, records);
+
+    Ok(Data::List {
+        span: Span {
+            start,
+            end: bytes.offset(),
+        },
+        identifier: identifier.as_deref(),
+        items: sub_parsed,
+    })
+}
+
+/// Apply a pattern to exactly the bytes of a region, with any it doesn't use left over as
+/// slack. Reading past the end of the region fails like reading past the end of the input.
+fn process_region<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    mut region: Reader<'b>,
+    exprs: &'a [Expr],
+    description: &str,
+) -> Result<(Vec<Data<'a, 'b>>, Record<'a, 'b>)> {
+    let start = region.offset();
+    let (data, record, overrun) = process_block(exprs, &mut region, stack, true)?;
+    region.align();
+
+    let Data::List { mut items, .. } = data else {
+        unreachable!("Scopes are always lists")
+    };
+    if let Some(err) = overrun {
+        stack.warnings.push(format!(
+            "{} at offset {:#x} stopped at offset {:#x} as it ran past the end: {}",
+            description,
+            start,
+            region.offset(),
+            err.root_cause()
+        ));
+    } else if !region.is_empty() {
+        stack.warnings.push(format!(
+            "{} at offset {:#x} only used {} of its {} bytes",
+            description,
+            start,
+            region.offset() - start,
+            region.offset() - start + region.remaining()
+        ));
+    }
+    // Whatever the block didn't get to is kept as slack, so the whole region is accounted for
+    if !region.is_empty() {
+        let slack_start = region.offset();
+        let slack = region.read(region.remaining())?;
+        items.push(Data::Slack {
+            span: Span {
+                start: slack_start,
+                end: region.offset(),
+            },
+            bytes: slack,
+        });
+    }
+
+    Ok((items, record))
+}
+
+/// Get the key for a cipher, either a single byte or a run of bytes
+fn eval_key(stack: &Stack, bytes: &Reader, key: &ValueExpr) -> Result<Vec<u8>> {
+    let key = match eval(stack, bytes, key)? {
+        Value::Int(x) => {
+            vec![u8::try_from(x).with_context(|| format!("Key {} doesn't fit in a byte", x))?]
         }
-        sub_parsed
+        Value::Bytes(key) => key.to_vec(),
+        Value::Array(_, array) => array.bytes().to_vec(),
+        val => bail!("Cannot use {} as a key", val.describe()),
     };
+    if key.is_empty() {
+        bail!("Key is empty");
+    }
 
-    Ok(Data::List(sub_parsed))
+    Ok(key)
+}
+
+/// Apply a pattern to the decompressed or deciphered contents of a field
+fn process_decode<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &Reader<'b>,
+    transform: &'a Transform,
+    source: &ValueExpr,
+    identifier: &'a Option<String>,
+    exprs: &'a [Expr],
+) -> Result<Data<'a, 'b>> {
+    let (span, encoded) = match eval(stack, bytes, source)? {
+        Value::Array(span, array) => (span, array.bytes()),
+        val => bail!("Cannot decode {}, it isn't a field", val.describe()),
+    };
+
+    let decoded = match transform {
+        Transform::Decompress(codec) => {
+            transform::decompress(*codec, encoded, stack.limits.max_alloc)
+        }
+        Transform::Cipher(cipher, key) => {
+            eval_key(stack, bytes, key).map(|key| transform::decipher(*cipher, encoded, &key))
+        }
+    }
+    .with_context(|| {
+        format!(
+            "Failed to decode {} stream at {:#x}..{:#x}",
+            transform.name(),
+            span.start,
+            span.end
+        )
+    })?;
+    // The tree borrows from the decoded bytes just like the input
+    let decoded: &'b [u8] = stack.buffers.alloc(decoded);
+
+    let description = format!("Decoded {} stream", transform.name());
+    let (items, record) = process_region(stack, Reader::new(decoded), exprs, &description)
+        .with_context(|| {
+            format!(
+                "In decoded {} stream at {:#x}",
+                transform.name(),
+                span.start
+            )
+        })?;
+    if let Some(id) = identifier {
+        stack.set_var(
+            id,
+            Value::Record(span, bytes.covered(span), Rc::new(record)),
+        );
+    }
+
+    Ok(Data::Decoded {
+        span,
+        identifier: identifier.as_deref(),
+        transform,
+        bytes: decoded,
+        items,
+    })
+}
+
+/// Take a pattern within exactly the next N bytes
+fn process_limit<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &mut Reader<'b>,
+    size: &ValueExpr,
+    identifier: &'a Option<String>,
+    exprs: &'a [Expr],
+) -> Result<Data<'a, 'b>> {
+    let size = eval(stack, bytes, size)?.as_int()?;
+    let mut size =
+        usize::try_from(size).with_context(|| format!("Cannot use {} as a LIMIT size", size))?;
+
+    bytes.align();
+    let start = bytes.offset();
+    if size > bytes.remaining() {
+        stack.warnings.push(format!(
+            "LIMIT block at offset {:#x} wants {} bytes, but only {} are left",
+            start,
+            size,
+            bytes.remaining()
+        ));
+        size = bytes.remaining();
+    }
+
+    let span = Span {
+        start,
+        end: start + size,
+    };
+    let (items, record) = process_region(stack, bytes.region(span), exprs, "LIMIT block")
+        .with_context(|| format!("In LIMIT block of {} bytes at offset {:#x}", size, start))?;
+    bytes.read(size)?;
+
+    if let Some(id) = identifier {
+        stack.set_var(
+            id,
+            Value::Record(span, bytes.covered(span), Rc::new(record)),
+        );
+    }
+
+    Ok(Data::List {
+        span,
+        identifier: identifier.as_deref(),
+        items,
+    })
 }
 
 /// Take a repeated pattern over the given iterator
-fn process_take_over<'a>(
-    stack: &mut Stack<'a>,
-    bytes: &mut Peekable<impl Iterator<Item = u8>>,
-    iter_identifier: &str,
+fn process_take_over<'a, 'b>(
+    stack: &mut Stack<'a, 'b>,
+    bytes: &mut Reader<'b>,
+    iter: &ValueExpr,
     index_identifier: &'a str,
+    identifier: &'a Option<String>,
     exprs: &'a [Expr],
-) -> Result<Data> {
-    // Search up the scope stack
-    let iter = stack
-        .get_var(iter_identifier)
-        .with_context(|| format!("Variable not found: {:?}", iter_identifier))?;
-
-    let items = match iter {
-        PrimativeArray::U8(items) => items.iter().map(|x| *x as usize).collect::<Vec<_>>(),
-        PrimativeArray::U16(items) => items.iter().map(|x| *x as usize).collect::<Vec<_>>(),
-        PrimativeArray::U32(items) => items.iter().map(|x| *x as usize).collect::<Vec<_>>(),
-        PrimativeArray::U64(items) => items.iter().map(|x| *x as usize).collect::<Vec<_>>(),
-        PrimativeArray::U128(_) => bail!("Cannot downcast u128 -> usize"),
-        _ => bail!("Cannot use dtype as count: {:?}", iter),
+) -> Result<Data<'a, 'b>> {
+    let items = match eval(stack, bytes, iter)? {
+        Value::Array(_, array) if array.text().is_some() => {
+            bail!("Cannot iterate over: {:?}", array)
+        }
+        Value::Array(span, array) => (0..array.len())
+            .map(|i| {
+                let (span, item) = array.item(span, i);
+                Value::Array(span, item)
+            })
+            .collect(),
+        Value::List(items) => items.to_vec(),
+        val => bail!("Cannot iterate over: {:?}", val),
     };
 
     // Add a new temp stack layer to store our loop variable
     stack.add_layer();
 
-    let sub_parsed = items
-        .into_iter()
-        .map(|i| {
-            stack.set_var(index_identifier, PrimativeArray::U64(vec![i as u64]));
+    let start = bytes.offset();
 
-            process_bytes(exprs, bytes, stack)
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let mut sub_parsed = vec![];
+    let mut records = vec![];
+    for item in items {
+        stack.tick()?;
+        stack.set_var(index_identifier, item);
+
+        let (data, record) = process_scope(exprs, bytes, stack)?;
+        if identifier.is_some() {
+            records.push((data.span(), record));
+        }
+        sub_parsed.push(data);
+    }
 
     // Remove the temp stack layer
     stack.remove_layer();
 
-    Ok(Data::List(sub_parsed))
+    bind_records(stack, bytes, identifier, records);
+
+    Ok(Data::List {
+        span: Span {
+            start,
+            end: bytes.offset(),
+        },
+        identifier: identifier.as_deref(),
+        items: sub_parsed,
+    })
+}
+
+/// Apply a pattern in a new scope, returning the parsed data and the variables it set.
+/// The scope is removed afterwards, so its variables are only reachable through the
+/// identifier of the enclosing block.
+fn process_scope<'a, 'b>(
+    pattern: &'a [Expr],
+    bytes: &mut Reader<'b>,
+    stack: &mut Stack<'a, 'b>,
+) -> Result<(Data<'a, 'b>, Record<'a, 'b>)> {
+    let (data, record, _) = process_block(pattern, bytes, stack, false)?;
+
+    Ok((data, record))
 }
 
-pub fn process_bytes<'a>(
+/// Apply a pattern within a new scope. If `bounded`, reading past the end of the reader stops
+/// the block before the expression which overran, and the error is returned alongside what
+/// was parsed up to then.
+fn process_block<'a, 'b>(
     pattern: &'a [Expr],
-    bytes: &mut Peekable<impl Iterator<Item = u8>>,
-    stack: &mut Stack<'a>,
-) -> Result<Data> {
+    bytes: &mut Reader<'b>,
+    stack: &mut Stack<'a, 'b>,
+    bounded: bool,
+) -> Result<(Data<'a, 'b>, Record<'a, 'b>, Option<anyhow::Error>)> {
+    stack.depth += 1;
+    if stack.depth > stack.limits.max_depth {
+        bail!(
+            "Exceeded the maximum pattern depth ({})",
+            stack.limits.max_depth
+        );
+    }
     stack.add_layer();
+    let (depth, layers) = (stack.depth, stack.variables.len());
 
+    let start = bytes.offset();
     let mut parsed = vec![];
+    let mut overrun = None;
     for p in pattern {
-        match p {
-            Expr::Primative {
-                dtype,
-                count,
-                identifier,
-            } => parsed.push(
-                process_primative(stack, bytes, dtype, count, identifier)
-                    .with_context(|| format!("Failed to apply pattern: {:?}", p))?,
-            ),
-            Expr::TakeN { count, exprs } => {
-                parsed.push(
-                    process_take_n(stack, bytes, count, exprs)
-                        .with_context(|| format!("Failed to apply pattern: {:?}", p))?,
-                );
+        let (pos, bit) = (bytes.pos, bytes.bit);
+        let result = process_expr(p, start, bytes, stack, &mut parsed)
+            .with_context(|| format!("Failed to apply {} at offset {:#x}", describe(p), pos));
+        match result {
+            Ok(()) => {}
+            Err(err) if bounded && bytes.overran(&err) => {
+                // Unwind whatever the failed expression left behind
+                (bytes.pos, bytes.bit) = (pos, bit);
+                stack.depth = depth;
+                stack.truncate_layers(layers);
+                overrun = Some(err);
+                break;
             }
-            Expr::TakeOver {
-                iter_identifier,
+            Err(err) => return Err(err),
+        }
+    }
+    stack.depth -= 1;
+
+    let data = Data::List {
+        span: Span {
+            start,
+            end: bytes.offset(),
+        },
+        identifier: None,
+        items: parsed,
+    };
+
+    Ok((data, stack.remove_layer(), overrun))
+}
+
+/// Short description of an expression for error messages
+fn describe(p: &Expr) -> String {
+    let named = |keyword: &str, identifier: &Option<String>| match identifier {
+        Some(id) => format!("{} {}", keyword, id),
+        None => keyword.to_string(),
+    };
+    match p {
+        Expr::Primative { identifier, .. } => named("field", identifier),
+        Expr::TakeN { identifier, .. } => named("TAKE_N", identifier),
+        Expr::TakeOver { identifier, .. } => named("TAKE_OVER", identifier),
+        Expr::Limit { identifier, .. } => named("LIMIT", identifier),
+        Expr::Decode { identifier, .. } => named("DECODE", identifier),
+        Expr::Let { identifier, .. } => format!("LET {}", identifier),
+        Expr::Assert(_) => "ASSERT".to_string(),
+        Expr::Enum(decl) => decl.identifier.clone(),
+        Expr::Struct { identifier, .. } => format!("STRUCT {}", identifier),
+        Expr::Align { .. } => "ALIGN".to_string(),
+        Expr::Pad(_) => "PAD".to_string(),
+        Expr::Bits { identifier, .. } => named("BITS", identifier),
+    }
+}
+
+/// Apply a single expression within a block starting at `start`, adding anything it parses to
+/// `parsed`
+fn process_expr<'a, 'b>(
+    p: &'a Expr,
+    start: usize,
+    bytes: &mut Reader<'b>,
+    stack: &mut Stack<'a, 'b>,
+    parsed: &mut Vec<Data<'a, 'b>>,
+) -> Result<()> {
+    match p {
+        Expr::Primative {
+            dtype,
+            count,
+            identifier,
+            expect,
+            checksum,
+            parse_as,
+        } => {
+            let mut data =
+                process_primative(stack, bytes, dtype, count, identifier, expect, parse_as)?;
+            if let Some((algorithm, over)) = checksum {
+                process_checksum(stack, bytes, &mut data, *algorithm, over)?;
+            }
+            parsed.push(data);
+        }
+        Expr::TakeN {
+            count,
+            identifier,
+            exprs,
+        } => {
+            parsed.push(process_take_n(stack, bytes, count, identifier, exprs)?);
+        }
+        Expr::TakeOver {
+            iter,
+            index_identifier,
+            identifier,
+            exprs,
+        } => {
+            parsed.push(process_take_over(
+                stack,
+                bytes,
+                iter,
                 index_identifier,
+                identifier,
                 exprs,
-            } => {
-                parsed.push(
-                    process_take_over(stack, bytes, iter_identifier, index_identifier, exprs)
-                        .with_context(|| format!("Failed to apply pattern: {:?}", p))?,
+            )?);
+        }
+        Expr::Limit {
+            size,
+            identifier,
+            exprs,
+        } => parsed.push(process_limit(stack, bytes, size, identifier, exprs)?),
+        Expr::Decode {
+            transform,
+            source,
+            identifier,
+            exprs,
+        } => parsed.push(process_decode(
+            stack, bytes, transform, source, identifier, exprs,
+        )?),
+        Expr::Let { identifier, value } => {
+            parsed.push(process_let(stack, bytes, identifier, value)?)
+        }
+        Expr::Assert(condition) => process_assert(stack, bytes, condition)?,
+        Expr::Enum(decl) => {
+            if !matches!(
+                decl.dtype,
+                DType::U8
+                    | DType::U16(_)
+                    | DType::U32(_)
+                    | DType::U64(_)
+                    | DType::U128(_)
+                    | DType::BitInt(..)
+            ) {
+                bail!(
+                    "{} needs an integer dtype, not {:?}",
+                    decl.identifier,
+                    decl.dtype
                 );
             }
+            stack.enums.insert(&decl.identifier, decl);
+        }
+        Expr::Struct { identifier, exprs } => {
+            stack.structs.insert(identifier, exprs);
+        }
+        Expr::Align { size, from_block } => {
+            let base = if *from_block { start } else { 0 };
+            parsed.extend(process_padding(stack, bytes, size, Some(base))?);
         }
+        Expr::Pad(size) => parsed.extend(process_padding(stack, bytes, size, None)?),
+        Expr::Bits {
+            dtype,
+            identifier,
+            fields,
+        } => parsed.push(process_bits(stack, bytes, dtype, identifier, fields)?),
     }
 
-    Ok(Data::List(parsed))
+    Ok(())
+}
+
+pub fn process_bytes<'a, 'b>(
+    pattern: &'a [Expr],
+    bytes: &mut Reader<'b>,
+    stack: &mut Stack<'a, 'b>,
+) -> Result<Data<'a, 'b>> {
+    Ok(process_scope(pattern, bytes, stack)?.0)
 }
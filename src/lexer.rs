@@ -9,9 +9,71 @@ pub enum Token {
     #[token("}")]
     RightBrace,
 
-    #[regex(r"\d+", |x| x.slice().parse::<u64>().expect("Failed to parse number"))]
+    #[token("[")]
+    LeftBracket,
+
+    #[token("]")]
+    RightBracket,
+
+    #[token("(")]
+    LeftParen,
+
+    #[token(")")]
+    RightParen,
+
+    #[token(",")]
+    Comma,
+
+    #[token("+")]
+    Plus,
+
+    #[token("-")]
+    Minus,
+
+    #[token("/")]
+    Slash,
+
+    #[token("%")]
+    Percent,
+
+    #[token("=")]
+    Equals,
+
+    #[token("==")]
+    EqualsEquals,
+
+    #[token("!=")]
+    NotEquals,
+
+    #[token("<")]
+    LessThan,
+
+    #[token("<=")]
+    LessEquals,
+
+    #[token(">")]
+    GreaterThan,
+
+    #[token(">=")]
+    GreaterEquals,
+
+    #[token(".")]
+    Dot,
+
+    #[token(":")]
+    Colon,
+
+    #[regex(r"\d[\d_]*", |x| parse_number(x.slice(), 10))]
+    #[regex(r"0x[0-9a-fA-F_]+", |x| parse_number(&x.slice()[2..], 16))]
+    #[regex(r"0o[0-7_]+", |x| parse_number(&x.slice()[2..], 8))]
+    #[regex(r"0b[01_]+", |x| parse_number(&x.slice()[2..], 2))]
+    #[regex(r"'([^'\\]|\\[^x]|\\x[0-9a-fA-F]{2})'", |x| parse_char(x.slice()))]
     Number(u64),
 
+    #[regex(r#""([^"\\]|\\.)*""#, |x| unescape(&x.slice()[1..x.slice().len() - 1], false))]
+    #[regex(r#"b"([^"\\]|\\.)*""#, |x| unescape(&x.slice()[2..x.slice().len() - 1], true))]
+    Bytes(Vec<u8>),
+
     #[token("*")]
     Wildcard,
 
@@ -30,6 +92,101 @@ pub enum Token {
     #[token("TAKE_OVER")]
     TakeOver,
 
-    #[regex("(u8|u(16|32|64|128)(be|le)|char)", |x| x.slice().to_string())]
+    #[token("LIMIT")]
+    Limit,
+
+    #[token("LET")]
+    Let,
+
+    #[token("EXPECT")]
+    Expect,
+
+    #[token("ASSERT")]
+    Assert,
+
+    #[token("BITS")]
+    Bits,
+
+    #[token("ENUM")]
+    Enum,
+
+    #[token("FLAGS")]
+    Flags,
+
+    #[token("ALIGN")]
+    Align,
+
+    #[token("BLOCK")]
+    Block,
+
+    #[token("PAD")]
+    Pad,
+
+    #[token("DECODE")]
+    Decode,
+
+    #[token("CHECKSUM")]
+    Checksum,
+
+    #[token("OVER")]
+    Over,
+
+    #[token("STRUCT")]
+    Struct,
+
+    #[token("AS")]
+    As,
+
+    #[regex(r"(u\d+(be|le)?|char)", |x| x.slice().to_string())]
+    #[regex("(uleb128|sleb128|vlq|zigzag)", |x| x.slice().to_string())]
+    #[regex("(cstring|string|pstring8|pstring(16|32)(be|le))(_(utf8|utf16(be|le)|latin1))?", |x| x.slice().to_string())]
+    #[regex("(utf8|utf16(be|le)|latin1)", |x| x.slice().to_string())]
     DType(String),
 }
+
+/// Parse a number with optional `_` separators
+fn parse_number(s: &str, radix: u32) -> Option<u64> {
+    u64::from_str_radix(&s.replace('_', ""), radix).ok()
+}
+
+/// Parse a quoted character literal such as `'A'` or `'\x1a'`
+fn parse_char(s: &str) -> Option<u64> {
+    match unescape(&s[1..s.len() - 1], false)?.as_slice() {
+        [c] => Some(*c as u64),
+        _ => None,
+    }
+}
+
+/// Resolve the escapes in the body of a string literal. Byte strings can only contain ASCII,
+/// other strings are encoded as UTF-8.
+fn unescape(s: &str, ascii_only: bool) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => b'\n',
+                'r' => b'\r',
+                't' => b'\t',
+                '0' => b'\0',
+                '\\' => b'\\',
+                '"' => b'"',
+                '\'' => b'\'',
+                'x' => {
+                    let hex = [chars.next()?, chars.next()?].iter().collect::<String>();
+                    u8::from_str_radix(&hex, 16).ok()?
+                }
+                _ => return None,
+            },
+            c if c.is_ascii() => c as u8,
+            _ if ascii_only => return None,
+            c => {
+                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+        };
+        bytes.push(c);
+    }
+
+    Some(bytes)
+}
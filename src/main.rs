@@ -1,15 +1,24 @@
-use std::fs;
+use std::{fs, io::Write};
 
+use anyhow::{Context, Result, anyhow, ensure};
 use chumsky::{IterParser, Parser as _};
-use clap::{Parser, command};
+use clap::{Parser, ValueEnum};
+use colored::Colorize;
 use display::{HexWriter, print_horizontal, print_vertical};
-use interpreter::{Stack, process_bytes};
+use input::Input;
+use interpreter::{Buffers, Data, Limits, Reader, Span, Stack, process_bytes};
 use logos::Logos;
 
+mod checksum;
+mod coverage;
 mod display;
+mod export;
+mod input;
 mod interpreter;
 mod lexer;
 mod parser;
+mod query;
+mod transform;
 
 #[derive(Parser)]
 #[command(name = "pattern-parser")]
@@ -20,50 +29,286 @@ struct Args {
 
     /// Path to the binary file to parse
     binary_file: String,
+
+    /// Memory-map the binary file instead of reading it all into memory
+    #[arg(long)]
+    mmap: bool,
+
+    /// Print the parsed data as a vertical tree instead of a hex dump
+    #[arg(long)]
+    vertical: bool,
+
+    /// Only display the fields overlapping the byte range starting at this offset
+    #[arg(long, value_parser = parse_number)]
+    offset: Option<usize>,
+
+    /// Length of the byte range to display
+    #[arg(long, value_parser = parse_number)]
+    length: Option<usize>,
+
+    /// Only display the subtree at this field path, e.g. `chunks[3]`
+    #[arg(long)]
+    path: Option<String>,
+
+    /// Print the values at this field path instead of displaying the file, e.g. `chunks[*].type`
+    #[arg(long)]
+    query: Option<String>,
+
+    /// Output format for --query
+    #[arg(long, value_enum, default_value_t = Format::Decoded)]
+    format: Format,
+
+    /// List the byte ranges which weren't touched by any field
+    #[arg(long)]
+    coverage: bool,
+
+    /// Only show the raw bytes of the input in the hex view, without the contents of
+    /// DECODE blocks
+    #[arg(long)]
+    hide_decoded: bool,
+
+    /// Fail if any bytes are left unparsed
+    #[arg(long)]
+    strict: bool,
+
+    /// Report failed EXPECT and ASSERT checks as warnings instead of stopping
+    #[arg(long)]
+    lenient: bool,
+
+    /// Maximum number of bytes a single field can take
+    #[arg(long, default_value_t = Limits::default().max_alloc)]
+    max_alloc: usize,
+
+    /// Maximum number of loop iterations over the whole file
+    #[arg(long, default_value_t = Limits::default().max_iterations)]
+    max_iterations: usize,
+
+    /// Maximum nesting depth of pattern blocks
+    #[arg(long, default_value_t = Limits::default().max_depth)]
+    max_depth: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// The raw bytes from the file
+    Raw,
+    /// Human readable values
+    Decoded,
+    /// JSON, with named fields as objects
+    Json,
 }
 
-fn main() {
+/// Parse a decimal or 0x-prefixed hex number
+fn parse_number(s: &str) -> Result<usize, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
-    let file = fs::read_to_string(&args.pattern_file).unwrap();
+    let file = fs::read_to_string(&args.pattern_file)
+        .with_context(|| format!("Failed to read {:?}", args.pattern_file))?;
 
     let tokens = lexer::Token::lexer(&file)
-        .collect::<Result<Vec<_>, _>>()
-        .expect("Failed to parse tokens.");
+        .spanned()
+        .map(|(token, span)| {
+            token.map_err(|_| anyhow!("Invalid token {:?} at {:?}", &file[span.clone()], span))
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("Failed to parse tokens")?;
 
     let parser = parser::expr_parser().repeated().collect::<Vec<_>>();
 
     let pattern = parser
         .parse(&tokens)
         .into_result()
-        .expect("Failed to parse pattern file.");
-    println!("{:#?}", pattern);
+        .map_err(|errs| anyhow!("Failed to parse pattern file: {:?}", errs))?;
+    if args.query.is_none() {
+        println!("{:#?}", pattern);
+    }
 
-    let png_bytes = fs::read(&args.binary_file).unwrap();
+    let input = if args.mmap {
+        Input::map(&args.binary_file)?
+    } else {
+        Input::read(&args.binary_file)?
+    };
 
-    let mut png_iter = png_bytes.into_iter().peekable();
+    let mut reader = Reader::new(&input);
 
-    let mut stack = Stack::new();
+    let buffers = Buffers::new();
+    let mut stack = Stack::new(&buffers)
+        .with_limits(Limits {
+            max_alloc: args.max_alloc,
+            max_iterations: args.max_iterations,
+            max_depth: args.max_depth,
+        })
+        .with_lenient_checks(args.lenient);
     let parsed =
-        process_bytes(&pattern, &mut png_iter, &mut stack).expect("Faild to apply pattern");
+        process_bytes(&pattern, &mut reader, &mut stack).context("Failed to apply pattern")?;
+    for warning in stack.warnings() {
+        eprintln!("{} {}", "Warning:".yellow(), warning);
+    }
+
+    if let Some(query) = &args.query {
+        let path = query::parse_path(query)?;
+        let selected = query::select(&parsed, &path);
+        ensure!(!selected.is_empty(), "Field not found: {:?}", query);
+
+        match args.format {
+            Format::Raw => {
+                let mut bytes = vec![];
+                selected
+                    .iter()
+                    .for_each(|d| export::raw_bytes(d, &mut bytes));
+                std::io::stdout().write_all(&bytes)?;
+            }
+            Format::Decoded => selected.iter().for_each(|d| print_vertical(d, &[])),
+            Format::Json => {
+                let json = if path.contains(&query::Segment::Wildcard) {
+                    serde_json::Value::Array(selected.iter().map(|d| export::to_json(d)).collect())
+                } else {
+                    export::to_json(selected[0])
+                };
+                println!("{}", serde_json::to_string_pretty(&json)?);
+            }
+        }
+    } else {
+        // The whole file is always parsed, so only the display is narrowed down
+        let mut selected = match &args.path {
+            Some(path) => {
+                let mut selected = query::select(&parsed, &query::parse_path(path)?);
+                ensure!(!selected.is_empty(), "Field not found: {:?}", path);
+
+                if selected.len() == 1 {
+                    selected.remove(0).clone()
+                } else {
+                    let items = selected.into_iter().cloned().collect::<Vec<_>>();
+                    Data::List {
+                        span: Span {
+                            start: items.iter().map(|d| d.span().start).min().unwrap(),
+                            end: items.iter().map(|d| d.span().end).max().unwrap(),
+                        },
+                        identifier: None,
+                        items,
+                    }
+                }
+            }
+            None => parsed.clone(),
+        };
+        if args.offset.is_some() || args.length.is_some() {
+            let start = args.offset.unwrap_or(0);
+            let window = Span {
+                start,
+                end: args
+                    .length
+                    .map_or(reader.len(), |len| start.saturating_add(len)),
+            };
+            selected = query::window(&selected, window).with_context(|| {
+                format!("No fields overlap {:#x}..{:#x}", window.start, window.end)
+            })?;
+        }
+
+        if args.vertical {
+            print_vertical(&selected, &[]);
+        } else {
+            let mut writer = HexWriter::new(130).with_decoded(!args.hide_decoded);
+            print_horizontal(&selected, &mut writer, &[]);
+            writer.flush();
+        }
+
+        println!(
+            "Parsed {} / {} bytes, {} trailing bytes",
+            reader.offset(),
+            reader.len(),
+            reader.remaining()
+        );
+    }
+
+    let uncovered = coverage::find_uncovered(&parsed, reader.len());
+    if args.coverage {
+        println!("Uncovered byte ranges:");
+        for span in &uncovered {
+            println!(
+                "  {:#x}..{:#x} ({} bytes)",
+                span.start,
+                span.end,
+                span.len()
+            );
+        }
+    }
 
-    //print_vertical(&parsed, &[]);
-    let mut writer = HexWriter::new(130);
-    print_horizontal(&parsed, &mut writer, &[]);
+    ensure!(
+        !args.strict || uncovered.is_empty(),
+        "{} bytes were left unparsed",
+        uncovered.iter().map(|s| s.len()).sum::<usize>()
+    );
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
 
+    use serde_json::json;
+
     use chumsky::{IterParser, Parser};
     use logos::Logos;
 
     use crate::{
-        display::print_vertical,
-        interpreter::{Stack, process_bytes},
-        lexer, parser,
+        checksum,
+        coverage::find_uncovered,
+        display::{HexWriter, print_horizontal, print_vertical},
+        export,
+        input::Input,
+        interpreter::{Buffers, Data, Limits, Reader, Span, Stack, process_bytes},
+        lexer,
+        parser::{self, Checksum},
+        query,
     };
 
+    /// Somewhere to keep decoded bytes for the rest of the test
+    fn buffers() -> &'static Buffers {
+        Box::leak(Box::default())
+    }
+
+    /// Apply an inline pattern to some bytes
+    fn apply<'b>(pattern: &str, bytes: &'b [u8]) -> anyhow::Result<Data<'static, 'b>> {
+        apply_with(pattern, bytes, &mut Stack::new(buffers()))
+    }
+
+    /// Apply an inline pattern to some bytes with the given stack, e.g. to set limits or see
+    /// the warnings
+    fn apply_with<'b>(
+        pattern: &str,
+        bytes: &'b [u8],
+        stack: &mut Stack<'static, 'b>,
+    ) -> anyhow::Result<Data<'static, 'b>> {
+        let tokens = lexer::Token::lexer(pattern)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let pattern = parser::expr_parser()
+            .repeated()
+            .collect::<Vec<_>>()
+            .parse(&tokens)
+            .into_result()
+            .expect("Failed to parse pattern");
+        // The parsed tree borrows identifiers from the pattern
+        let pattern = Vec::leak(pattern);
+
+        let mut reader = Reader::new(bytes);
+        process_bytes(pattern, &mut reader, stack)
+    }
+
+    /// Look up a field by its path
+    fn get<'d>(data: &'d Data<'static, 'd>, path: &str) -> &'d Data<'static, 'd> {
+        query::select(data, &query::parse_path(path).unwrap())
+            .first()
+            .unwrap_or_else(|| panic!("Field not found: {:?}", path))
+    }
+
     #[test]
     fn test_png() {
         let file = fs::read_to_string("./data/patterns/png.pattern").unwrap();
@@ -82,17 +327,23 @@ mod tests {
 
         let png_bytes = fs::read("./data/binary_files/image.png").unwrap();
 
-        let mut png_iter = png_bytes.into_iter().peekable();
+        let mut reader = Reader::new(&png_bytes);
 
-        let mut stack = Stack::new();
+        let mut stack = Stack::new(buffers());
         let parsed =
-            process_bytes(&pattern, &mut png_iter, &mut stack).expect("Faild to apply pattern");
+            process_bytes(&pattern, &mut reader, &mut stack).expect("Faild to apply pattern");
         println!("{:?}", parsed);
 
         print_vertical(&parsed, &[]);
+
+        assert_eq!(reader.remaining(), 0);
+        assert!(find_uncovered(&parsed, png_bytes.len()).is_empty());
+        // Every chunk's CRC matches
+        assert!(stack.warnings().is_empty());
     }
 
     #[test]
+    #[ignore = "needs a local copy of the PoE Bundles2 data"]
     fn test_poe_bundle_index() {
         let file = fs::read_to_string("./data/patterns/poe_bundle.pattern").unwrap();
 
@@ -110,14 +361,671 @@ mod tests {
 
         let png_bytes = fs::read("/mnt/nvme_4tb/programming/data/poe/cache/patch-poe2.poecdn.com/4.2.0.13/Bundles2/_.index.bin").unwrap();
 
-        let mut png_iter = png_bytes.into_iter().peekable();
+        let mut reader = Reader::new(&png_bytes);
 
-        let mut stack = Stack::new();
+        let mut stack = Stack::new(buffers());
         let parsed =
-            process_bytes(&pattern, &mut png_iter, &mut stack).expect("Faild to apply pattern");
+            process_bytes(&pattern, &mut reader, &mut stack).expect("Faild to apply pattern");
         println!("{:?}", parsed);
 
         print_vertical(&parsed, &[]);
         panic!()
     }
+
+    #[test]
+    fn test_limits() {
+        // Zero-length bodies would loop forever
+        let err = apply("TAKE_N * { u8 0 _ }", &[1, 2, 3]).unwrap_err();
+        assert!(format!("{:?}", err).contains("consumed no bytes"));
+
+        // Corrupt length fields shouldn't be trusted
+        let err = apply("u32le 1 n u8 n _", &[0xff, 0xff, 0xff, 0x00]).unwrap_err();
+        assert!(format!("{:?}", err).contains("Ran out of bytes"));
+
+        let limited = |max_alloc, max_iterations, max_depth| {
+            Stack::new(buffers()).with_limits(Limits {
+                max_alloc,
+                max_iterations,
+                max_depth,
+            })
+        };
+        let bytes = [0; 16];
+
+        let mut stack = limited(8, 100, 8);
+        let err = apply_with("u8 4 a u32le 4 b", &bytes, &mut stack).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("Primative of 16 bytes exceeds the allocation limit (8)")
+        );
+
+        let mut stack = limited(16, 10, 8);
+        let err = apply_with("TAKE_N * { u8 1 _ }", &bytes, &mut stack).unwrap_err();
+        assert!(format!("{:?}", err).contains("Exceeded the maximum number of iterations (10)"));
+
+        let mut stack = limited(16, 100, 2);
+        let err = apply_with("TAKE_N 1 { TAKE_N 1 { u8 1 _ } }", &bytes, &mut stack).unwrap_err();
+        assert!(format!("{:?}", err).contains("Exceeded the maximum pattern depth (2)"));
+        // Up to the limit is fine
+        let mut stack = limited(16, 100, 2);
+        assert!(apply_with("TAKE_N 1 { u8 1 _ }", &bytes, &mut stack).is_ok());
+    }
+
+    #[test]
+    fn test_input() {
+        let path = "./data/binary_files/image.png";
+        let mapped = Input::map(path).unwrap();
+        let read = Input::read(path).unwrap();
+
+        assert!(matches!(mapped, Input::Mapped(_)));
+        assert_eq!(*mapped, *read);
+        assert_eq!(*read, fs::read(path).unwrap());
+    }
+
+    #[test]
+    fn test_take_over() {
+        let bytes = [2, 0, 1, 0, 0xaa, 0xbb, 0xcc];
+        let parsed = apply("u16le 2 sizes TAKE_OVER sizes size { u8 size _ }", &bytes).unwrap();
+
+        let sizes = (0..2)
+            .map(|i| {
+                let block = get(&parsed, &format!("[1][{}][0]", i));
+                (block.span().start, block.span().len())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(4, 2), (6, 1)]);
+    }
+
+    #[test]
+    fn test_select() {
+        let bytes = fs::read("./data/binary_files/image.png").unwrap();
+        let file = fs::read_to_string("./data/patterns/png.pattern").unwrap();
+        let parsed = apply(&file, &bytes).unwrap();
+
+        let Data::Primative { array, .. } = get(&parsed, "chunks[0].type") else {
+            panic!()
+        };
+        assert_eq!(array.bytes(), b"IHDR");
+
+        // Fields partially inside the window are kept whole
+        let window = query::window(&parsed, Span { start: 10, end: 13 }).unwrap();
+        let Data::Primative { span, .. } = get(&window, "chunks[0].n") else {
+            panic!()
+        };
+        assert_eq!(*span, Span { start: 8, end: 12 });
+        assert_eq!(get(&window, "chunks[0].type").span().start, 12);
+        assert!(query::select(&window, &query::parse_path("chunks[0].data").unwrap()).is_empty());
+
+        let types = query::select(&parsed, &query::parse_path("chunks[*].type").unwrap())
+            .into_iter()
+            .map(export::to_json)
+            .collect::<Vec<_>>();
+        assert_eq!(types, ["IHDR", "sBIT", "tEXt", "tEXt", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn test_index() {
+        let bytes = [3, 1, 2, 0xaa, 0xbb, 0xcc];
+        let parsed = apply(
+            "u8 3 sizes u8 sizes[-1] last u8 sizes[sizes[1]] first",
+            &bytes,
+        )
+        .unwrap();
+
+        assert_eq!(get(&parsed, "last").span(), Span { start: 3, end: 5 });
+        assert_eq!(get(&parsed, "first").span(), Span { start: 5, end: 6 });
+        assert!(apply("u8 1 sizes u8 sizes[1] _", &bytes).is_err());
+    }
+
+    #[test]
+    fn test_scoping() {
+        let bytes = [2, 2, 1, 3, 0xaa, 0xbb, 0xcc];
+        let pattern = "
+            TAKE_N 1 header { u8 1 version u8 1 count }
+            TAKE_N header.count records { u8 1 size }
+            u8 records[-1].size tail
+        ";
+        let parsed = apply(pattern, &bytes).unwrap();
+        assert_eq!(get(&parsed, "tail").span(), Span { start: 4, end: 7 });
+
+        // Fields don't leak out of the block they were parsed in
+        assert!(apply("TAKE_N 1 { u8 1 n } u8 n _", &bytes).is_err());
+    }
+
+    #[test]
+    fn test_let() {
+        let bytes = [3, 1, 2, 0xa0, 0xa1, 0xa2, 0xb0, 0xc0, 0xc1, 0xd0];
+        let pattern = "
+            u8 3 sizes
+            LET total = 0
+            TAKE_OVER sizes size blocks {
+                LET start = total
+                u8 size _
+                LET total = total + size
+            }
+            u8 (total - sizes[0] * 2 + 1) tail
+        ";
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        let starts = query::select(&parsed, &query::parse_path("blocks[*].start").unwrap())
+            .into_iter()
+            .map(export::to_json)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0, 3, 4]);
+        assert_eq!(export::to_json(get(&parsed, "total")), 0);
+        assert_eq!(get(&parsed, "tail").span(), Span { start: 9, end: 10 });
+
+        // A LET doesn't change a parsed field, it binds a new variable in its own block
+        let parsed = apply(
+            "u8 1 size TAKE_N 2 { LET size = 9 } u8 size rest",
+            &[1, 2, 3],
+        )
+        .unwrap();
+        assert_eq!(get(&parsed, "rest").span(), Span { start: 1, end: 2 });
+
+        // Errors show the expression as it was written
+        let err = apply("u8 2 x LET y = x[1] / (x[0] - 1) * 2", &[1, 2]).unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(
+            err.contains("Invalid arithmetic: x[1] / (x[0] - 1)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_builtins() {
+        let bytes = [0; 10];
+        let pattern = "
+            u16le 2 header
+            u8 (FILE_SIZE - OFFSET - 2) body
+            LET n = max(len(body), sizeof(header), 3)
+            LET m = min(sizeof(u32be), REMAINING)
+            u8 REMAINING tail
+        ";
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(get(&parsed, "body").span(), Span { start: 4, end: 8 });
+        assert_eq!(export::to_json(get(&parsed, "n")), 4);
+        assert_eq!(export::to_json(get(&parsed, "m")), 2);
+        assert_eq!(get(&parsed, "tail").span(), Span { start: 8, end: 10 });
+    }
+
+    #[test]
+    fn test_literals() {
+        let bytes = [0; 300];
+        let pattern = r#"
+            u8 0x0_2 a
+            u8 0b11 b
+            u8 (0o10 + 'A' - 65) c
+            LET magic = b"\x89PNG\r\n"
+            LET name = "héllo"
+            u8 (len(magic) + len(name)) d
+        "#;
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(get(&parsed, "a").span(), Span { start: 0, end: 2 });
+        assert_eq!(get(&parsed, "b").span(), Span { start: 2, end: 5 });
+        assert_eq!(get(&parsed, "c").span(), Span { start: 5, end: 13 });
+        assert_eq!(export::to_json(get(&parsed, "name")), "héllo");
+        assert_eq!(get(&parsed, "d").span(), Span { start: 13, end: 25 });
+    }
+
+    #[test]
+    fn test_expect() {
+        let pattern = r#"
+            char 4 magic EXPECT b"\x7fELF"
+            u8 1 class
+            ASSERT class <= 2
+        "#;
+
+        assert!(apply(pattern, b"\x7fELF\x01").is_ok());
+
+        let err = format!("{:#}", apply(pattern, b"\x89PNG\x01").unwrap_err());
+        assert!(err.contains("EXPECT failed at offset 0x0"), "{}", err);
+        assert!(err.contains(r#"found b"\x89PNG""#), "{}", err);
+        assert!(
+            err.starts_with("Failed to apply field magic at offset 0x0"),
+            "{}",
+            err
+        );
+
+        let err = format!("{:#}", apply(pattern, b"\x7fELF\x03").unwrap_err());
+        assert!(err.contains("ASSERT failed at offset 0x5"), "{}", err);
+        assert!(
+            err.contains("ASSERT failed at offset 0x5: class <= 2 (left: 3, right: 2)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let bytes = b"abc\0\x02hi\x00\x01x\x00\x00ok\0\0\0\0\0\0";
+        let pattern = r#"
+            cstring 1 name EXPECT "abc"
+            pstring8 1 tag
+            pstring16be 2 parts
+            string 8 padded
+            ASSERT padded == "ok"
+            ASSERT len(name) == 3
+            ASSERT len(tag) == 2
+            ASSERT len(padded) == 2
+        "#;
+        let parsed = apply(pattern, bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "name")), "abc");
+        assert_eq!(get(&parsed, "name").span(), Span { start: 0, end: 4 });
+        assert_eq!(export::to_json(get(&parsed, "tag")), "hi");
+        assert_eq!(export::to_json(get(&parsed, "parts")), json!(["x", ""]));
+        assert_eq!(export::to_json(get(&parsed, "padded")), "ok");
+        assert_eq!(get(&parsed, "padded").span(), Span { start: 12, end: 20 });
+
+        let err = format!("{:#}", apply("cstring 1 _", b"abc").unwrap_err());
+        assert!(
+            err.contains("Unterminated cstring at offset 0x0"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_encodings() {
+        let bytes = b"h\0i\0\0\0\x02\0\x3d\xd8\x00\xdc\xe9\xc3\xa9\xff";
+        let pattern = r#"
+            cstring_utf16le 1 name EXPECT "hi"
+            pstring16le_utf16le 1 emoji
+            latin1 1 e
+            utf8 3 invalid
+            ASSERT len(name) == 2
+        "#;
+        let parsed = apply(pattern, bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "name")), "hi");
+        assert_eq!(get(&parsed, "name").span(), Span { start: 0, end: 6 });
+        assert_eq!(export::to_json(get(&parsed, "emoji")), "\u{1f400}");
+        assert_eq!(export::to_json(get(&parsed, "e")), "é");
+        assert_eq!(export::to_json(get(&parsed, "invalid")), "é\u{fffd}");
+    }
+
+    #[test]
+    fn test_bits() {
+        let pattern = "
+            BITS u16le flags { compressed:1 level:4 _:10 last:1 }
+            ASSERT level == 5
+            ASSERT flags.last == 1
+        ";
+        let parsed = apply(pattern, &[0b1010_1011, 0x80]).unwrap();
+
+        assert_eq!(
+            export::to_json(get(&parsed, "flags")),
+            json!({"compressed": 1, "level": 5, "last": 1})
+        );
+        assert_eq!(
+            get(&parsed, "flags.level").span(),
+            Span { start: 0, end: 2 }
+        );
+
+        let err = format!("{:#}", apply("BITS u8 { a:4 b:5 }", &[0]).unwrap_err());
+        assert!(err.contains("Bit fields take 9 bits"), "{}", err);
+        let pattern = "BITS u8 { a:18446744073709551615 b:1 }";
+        assert!(apply(pattern, &[0]).is_err());
+    }
+
+    #[test]
+    fn test_bit_ints() {
+        let pattern = "
+            u3 1 a
+            u5 1 b
+            u12be 1 c
+            u3le 1 d
+            u8 1 e
+            TAKE_N * nibbles { u4 1 n }
+        ";
+        let bytes = [0b1010_1100, 0b1010_0000, 0xf0, 0x06, 0x12];
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "a")), 5);
+        assert_eq!(export::to_json(get(&parsed, "b")), 12);
+        assert_eq!(export::to_json(get(&parsed, "c")), 2575);
+        assert_eq!(get(&parsed, "c").span(), Span { start: 1, end: 3 });
+        assert_eq!(export::to_json(get(&parsed, "d")), 7);
+        // Byte sized reads skip the rest of a partially read byte
+        assert_eq!(export::to_json(get(&parsed, "e")), 6);
+        assert_eq!(
+            export::to_json(get(&parsed, "nibbles")),
+            json!([{"n": 1}, {"n": 2}])
+        );
+
+        let parsed = apply("u4 4 x ASSERT x[3] == 4 ASSERT x[-2] == 3", &[0x12, 0x34]).unwrap();
+        assert_eq!(export::to_json(get(&parsed, "x")), json!([1, 2, 3, 4]));
+
+        // Out of range widths and byte sized integers without an endianness are rejected
+        for (pattern, message) in [
+            ("u0 1 x", "Integers must be 1 to 128 bits wide"),
+            ("u200 1 x", "Integers must be 1 to 128 bits wide"),
+            ("u16 1 x", "needs an endianness suffix"),
+            ("u32 1 x", "needs an endianness suffix"),
+            ("u8le 1 x", "doesn't need an endianness suffix"),
+        ] {
+            let tokens = lexer::Token::lexer(pattern)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let errs = parser::expr_parser()
+                .parse(&tokens)
+                .into_result()
+                .unwrap_err();
+            assert!(format!("{:?}", errs).contains(message), "{:?}", errs);
+        }
+    }
+
+    #[test]
+    fn test_varints() {
+        let pattern = "
+            uleb128 1 big
+            uleb128 1 n
+            u8 n data
+            sleb128 1 s
+            vlq 1 v
+            zigzag * z
+        ";
+        let bytes = [
+            0xe5, 0x8e, 0x26, 0x02, 0xaa, 0xbb, 0xc0, 0xbb, 0x78, 0x81, 0x00, 0x03, 0x04,
+        ];
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "big")), 624485);
+        assert_eq!(get(&parsed, "big").span(), Span { start: 0, end: 3 });
+        assert_eq!(export::to_json(get(&parsed, "data")), json!([0xaa, 0xbb]));
+        assert_eq!(export::to_json(get(&parsed, "s")), -123456);
+        assert_eq!(export::to_json(get(&parsed, "v")), 128);
+        assert_eq!(export::to_json(get(&parsed, "z")), json!([-2, 2]));
+
+        // The last byte of a varint has its top bit clear
+        assert!(apply("uleb128 1 x", &[0x80, 0x80]).is_err());
+
+        // The most negative value can't be negated
+        let mut bytes = [0x80; 19];
+        bytes[18] = 0x02;
+        let err = format!("{:#}", apply("sleb128 1 s LET y = -s", &bytes).unwrap_err());
+        assert!(err.contains("Invalid arithmetic: -s"), "{}", err);
+    }
+
+    #[test]
+    fn test_enum() {
+        let pattern = "
+            ENUM TiffTag : u16le { ImageWidth = 256, ImageLength = 257, }
+            TiffTag 3 tags
+            ASSERT tags[1] == TiffTag.ImageLength
+            ASSERT sizeof(TiffTag) == 2
+        ";
+        let parsed = apply(pattern, &[0, 1, 1, 1, 0xff, 0xff]).unwrap();
+
+        assert_eq!(
+            export::to_json(get(&parsed, "tags")),
+            json!(["ImageWidth", "ImageLength", 65535])
+        );
+        assert_eq!(get(&parsed, "tags").span(), Span { start: 0, end: 6 });
+    }
+
+    #[test]
+    fn test_flags() {
+        let pattern = "
+            FLAGS Attr : u8 { READ = 0x01, HIDDEN = 0x02, SYSTEM = 0x04 }
+            Attr 3 attrs
+            ASSERT sizeof(Attr) == 1
+        ";
+        let parsed = apply(pattern, &[0x03, 0x00, 0x44]).unwrap();
+
+        assert_eq!(
+            export::to_json(get(&parsed, "attrs")),
+            json!([["READ", "HIDDEN"], [], ["SYSTEM", 0x40]])
+        );
+    }
+
+    #[test]
+    fn test_padding() {
+        let pattern = "
+            u8 1 a
+            ALIGN 4
+            TAKE_N 2 items {
+                u8 1 tag
+                ALIGN 2 BLOCK
+            }
+            PAD 2
+            u8 1 b
+        ";
+        let bytes = [1, 0, 0, 0, 2, 0, 3, 0, 0xff, 0xff, 4];
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(get(&parsed, "items").span(), Span { start: 4, end: 8 });
+        assert_eq!(export::to_json(get(&parsed, "b")), 4);
+        assert!(find_uncovered(&parsed, bytes.len()).is_empty());
+    }
+
+    #[test]
+    fn test_limit() {
+        let pattern = "
+            LIMIT 4 chunk {
+                u8 1 a
+                u8 * rest
+            }
+            ASSERT chunk.a == 1
+            LIMIT 3 short { u8 1 b }
+            TAKE_N * tail { u8 1 x }
+        ";
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8];
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(
+            export::to_json(get(&parsed, "chunk.rest")),
+            json!([2, 3, 4])
+        );
+        assert_eq!(export::to_json(get(&parsed, "short.b")), 5);
+        assert_eq!(get(&parsed, "short").span(), Span { start: 4, end: 7 });
+        assert_eq!(export::to_json(get(&parsed, "tail")), json!([{"x": 8}]));
+        // The bytes the block didn't use are left over as slack
+        assert_eq!(
+            find_uncovered(&parsed, bytes.len()),
+            [Span { start: 5, end: 7 }]
+        );
+
+        // Reading past the end stops the block with a warning, and the region is left as slack
+        let mut stack = Stack::new(buffers());
+        let parsed = apply_with(
+            "LIMIT 2 chunk { u8 1 a u16le 1 b } u8 1 y",
+            &[1, 2, 3],
+            &mut stack,
+        )
+        .unwrap();
+        assert_eq!(export::to_json(get(&parsed, "chunk.a")), 1);
+        assert_eq!(export::to_json(get(&parsed, "y")), 3);
+        assert_eq!(find_uncovered(&parsed, 3), [Span { start: 1, end: 2 }]);
+        assert_eq!(stack.warnings().len(), 1);
+        assert!(
+            stack.warnings()[0].contains("stopped at offset 0x1"),
+            "{}",
+            stack.warnings()[0]
+        );
+    }
+
+    #[test]
+    fn test_parse_as() {
+        let pattern = "
+            STRUCT Header {
+                u8 1 kind
+                u16le 1 size
+            }
+            u8 1 len
+            u8 len header AS Header
+            ASSERT header.size == 16
+            u8 1 after
+        ";
+        let bytes = [4, 7, 0x10, 0x00, 0xee, 9];
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(
+            export::to_json(get(&parsed, "header")),
+            json!({"kind": 7, "size": 16})
+        );
+        // Offsets within the field are offsets in the whole file
+        assert_eq!(
+            get(&parsed, "header.size").span(),
+            Span { start: 2, end: 4 }
+        );
+        assert_eq!(export::to_json(get(&parsed, "after")), 9);
+        assert_eq!(
+            find_uncovered(&parsed, bytes.len()),
+            [Span { start: 4, end: 5 }]
+        );
+
+        // Structs made of fixed size fields can be sized, e.g. to read a table of them
+        let pattern = "
+            STRUCT Entry { u16le 1 id TAKE_N 2 { u8 1 x } PAD 1 }
+            LET n = sizeof(Entry)
+            u8 n entry AS Entry
+        ";
+        let parsed = apply(pattern, &[1, 0, 2, 3, 0]).unwrap();
+        assert_eq!(export::to_json(get(&parsed, "n")), 5);
+
+        let err = apply("STRUCT S { u8 1 n u8 n data } LET x = sizeof(S)", &[]).unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(
+            err.contains("field data doesn't have a fixed size"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        use std::io::Write;
+
+        use flate2::{Compression, write::ZlibEncoder};
+
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&[3, b'a', b'b', b'c', 0xff]).unwrap();
+        let stream = encoder.finish().unwrap();
+
+        let pattern = "
+            u8 1 len
+            u8 len data
+            DECODE zlib data inner {
+                u8 1 n
+                char n text
+            }
+            ASSERT inner.n == 3
+            u8 1 after
+        ";
+        let mut bytes = vec![stream.len() as u8];
+        bytes.extend_from_slice(&stream);
+        bytes.push(0x42);
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "inner.text")), "abc");
+        // Fields in the stream have offsets into the decoded bytes
+        assert_eq!(get(&parsed, "inner.text").span(), Span { start: 1, end: 4 });
+        assert_eq!(
+            get(&parsed, "inner").span(),
+            Span {
+                start: 1,
+                end: 1 + stream.len()
+            }
+        );
+        assert_eq!(export::to_json(get(&parsed, "after")), 0x42);
+        assert!(find_uncovered(&parsed, bytes.len()).is_empty());
+
+        assert!(apply("u8 * data DECODE gzip data { }", &stream).is_err());
+    }
+
+    #[test]
+    fn test_ciphers() {
+        let pattern = "
+            u8 1 key
+            u8 3 data
+            DECODE xor(key) data plain {
+                char 3 text
+                DECODE add(1) data[1] next { char 1 c }
+            }
+            DECODE sub(1) data shifted { u8 * x }
+            u8 9 secret
+            DECODE rc4(b\"Key\") secret message { char 9 text }
+        ";
+        let mut bytes = vec![0x20, b'A', b'B', b'C'];
+        bytes.extend_from_slice(&[0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        assert_eq!(export::to_json(get(&parsed, "plain.text")), "abc");
+        // Fields of the enclosing input can be decoded from within a decoded stream
+        assert_eq!(export::to_json(get(&parsed, "plain.next.c")), "C");
+        assert_eq!(get(&parsed, "plain.next").span(), Span { start: 2, end: 3 });
+        assert_eq!(
+            export::to_json(get(&parsed, "shifted.x")),
+            json!([0x40, 0x41, 0x42])
+        );
+        assert_eq!(export::to_json(get(&parsed, "message.text")), "Plaintext");
+    }
+
+    #[test]
+    fn test_checksum() {
+        let pattern = "
+            char 9 data
+            u32le 1 adler CHECKSUM adler32 OVER data
+            u16be 1 crc CHECKSUM crc16_ccitt OVER data
+            u8 16 digest CHECKSUM md5 OVER b\"\"
+        ";
+        let mut bytes = b"Wikipedia".to_vec();
+        bytes.extend_from_slice(&0x11e60398u32.to_le_bytes());
+        // The check value of CRC-16/CCITT-FALSE is for "123456789", so this one is wrong
+        bytes.extend_from_slice(&0x29b1u16.to_be_bytes());
+        bytes.extend_from_slice(&[
+            0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
+            0x42, 0x7e,
+        ]);
+        let parsed = apply(pattern, &bytes).unwrap();
+
+        let valid = |path| match get(&parsed, path) {
+            Data::Primative {
+                checksum: Some(check),
+                ..
+            } => check.valid,
+            _ => panic!("{} isn't a checksum", path),
+        };
+        assert!(valid("adler"));
+        assert!(!valid("crc"));
+        assert!(valid("digest"));
+
+        // Named blocks are covered pass by pass, using the bytes from their own input
+        let pattern = "
+            TAKE_N 2 items { u8 1 x }
+            u8 1 sum CHECKSUM fnv1a32 OVER items
+            DECODE xor(0xff) items[0].x inverted { TAKE_N 1 inner { u8 1 y } }
+            u8 1 inner_sum CHECKSUM fnv1a32 OVER inverted.inner
+        ";
+        let parsed = apply(pattern, &[0x01, 0x02, 0x00, 0x00]).unwrap();
+        let computed = |path| match get(&parsed, path) {
+            Data::Primative {
+                checksum: Some(check),
+                ..
+            } => check.computed.clone(),
+            _ => panic!("{} isn't a checksum", path),
+        };
+        assert_eq!(
+            computed("sum"),
+            checksum::compute(Checksum::Fnv1a32, &[1, 2])
+        );
+        assert_eq!(
+            computed("inner_sum"),
+            checksum::compute(Checksum::Fnv1a32, &[0xfe])
+        );
+
+        // Empty checksum fields have no room for their label in the hex view
+        for pattern in [
+            "u8 0 c CHECKSUM crc32 OVER b\"\"",
+            "u3 1 a u8 0 c CHECKSUM crc32 OVER a",
+        ] {
+            let parsed = apply(pattern, &[0]).unwrap();
+            let mut writer = HexWriter::new(130);
+            print_horizontal(&parsed, &mut writer, &[]);
+            writer.flush();
+        }
+    }
 }
@@ -1,18 +1,184 @@
+use std::fmt;
+
 use crate::lexer::Token;
 use chumsky::{
     IterParser, Parser,
     error::Rich,
     extra,
-    prelude::{just, recursive},
+    prelude::{Recursive, choice, just, recursive},
     select,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Endianness {
     Big,
     Little,
 }
 
+/// How the bytes of a string are turned into text
+#[derive(Clone, Copy, Debug)]
+pub enum Encoding {
+    Ascii,
+    Utf8,
+    Utf16(Endianness),
+    Latin1,
+}
+
+impl Encoding {
+    /// Number of bytes in a single code unit
+    pub fn unit_size(&self) -> usize {
+        match self {
+            Encoding::Utf16(_) => 2,
+            Encoding::Ascii | Encoding::Utf8 | Encoding::Latin1 => 1,
+        }
+    }
+}
+
+/// Encodings of integers which take a variable number of bytes
+#[derive(Clone, Copy, Debug)]
+pub enum Varint {
+    /// 7 bits per byte, least significant group first
+    Uleb128,
+    /// Signed LEB128
+    Sleb128,
+    /// 7 bits per byte, most significant group first
+    Vlq,
+    /// Protobuf's signed varint, LEB128 with the sign in the lowest bit
+    ZigZag,
+}
+
+/// Compression formats which DECODE can unpack
+#[derive(Clone, Copy, Debug)]
+pub enum Codec {
+    Zlib,
+    /// Raw deflate, without a zlib header
+    Deflate,
+    Gzip,
+    /// LZ4 frames
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zlib => "zlib",
+            Codec::Deflate => "deflate",
+            Codec::Gzip => "gzip",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+/// Simple ciphers which DECODE can undo, each taking a key
+#[derive(Clone, Copy, Debug)]
+pub enum Cipher {
+    /// XOR with a repeating key
+    Xor,
+    /// Add a repeating key to each byte, wrapping around
+    Add,
+    /// Subtract a repeating key from each byte, wrapping around
+    Sub,
+    Rc4,
+}
+
+impl Cipher {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cipher::Xor => "xor",
+            Cipher::Add => "add",
+            Cipher::Sub => "sub",
+            Cipher::Rc4 => "rc4",
+        }
+    }
+}
+
+/// Algorithms a field can be declared as the CHECKSUM of
+#[derive(Clone, Copy, Debug)]
+pub enum Checksum {
+    Crc16Arc,
+    /// CRC-16/CCITT-FALSE
+    Crc16Ccitt,
+    Crc16Kermit,
+    Crc16Modbus,
+    Crc16Xmodem,
+    /// The CRC-32 used by PNG, zip and gzip
+    Crc32,
+    /// CRC-32C (Castagnoli)
+    Crc32c,
+    Adler32,
+    Fnv1a32,
+    Fnv1a64,
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl Checksum {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let checksum = match name {
+            "crc16_arc" => Checksum::Crc16Arc,
+            "crc16_ccitt" => Checksum::Crc16Ccitt,
+            "crc16_kermit" => Checksum::Crc16Kermit,
+            "crc16_modbus" => Checksum::Crc16Modbus,
+            "crc16_xmodem" => Checksum::Crc16Xmodem,
+            "crc32" => Checksum::Crc32,
+            "crc32c" => Checksum::Crc32c,
+            "adler32" => Checksum::Adler32,
+            "fnv1a32" => Checksum::Fnv1a32,
+            "fnv1a64" => Checksum::Fnv1a64,
+            "md5" => Checksum::Md5,
+            "sha1" => Checksum::Sha1,
+            "sha256" => Checksum::Sha256,
+            _ => return None,
+        };
+
+        Some(checksum)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Checksum::Crc16Arc => "crc16_arc",
+            Checksum::Crc16Ccitt => "crc16_ccitt",
+            Checksum::Crc16Kermit => "crc16_kermit",
+            Checksum::Crc16Modbus => "crc16_modbus",
+            Checksum::Crc16Xmodem => "crc16_xmodem",
+            Checksum::Crc32 => "crc32",
+            Checksum::Crc32c => "crc32c",
+            Checksum::Adler32 => "adler32",
+            Checksum::Fnv1a32 => "fnv1a32",
+            Checksum::Fnv1a64 => "fnv1a64",
+            Checksum::Md5 => "md5",
+            Checksum::Sha1 => "sha1",
+            Checksum::Sha256 => "sha256",
+        }
+    }
+
+    /// Whether the checksum is an integer rather than a digest of bytes, so it's compared
+    /// by value whatever the endianness of the field
+    pub fn is_int(&self) -> bool {
+        !matches!(self, Checksum::Md5 | Checksum::Sha1 | Checksum::Sha256)
+    }
+}
+
+/// How DECODE gets from the bytes of a field to the bytes its pattern is applied to
+#[derive(Clone, Debug)]
+pub enum Transform {
+    Decompress(Codec),
+    /// A cipher along with its key
+    Cipher(Cipher, ValueExpr),
+}
+
+impl Transform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::Decompress(codec) => codec.name(),
+            Transform::Cipher(cipher, _) => cipher.name(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum DType {
     U8,
@@ -21,12 +187,194 @@ pub enum DType {
     U64(Endianness),
     U128(Endianness),
     Char,
+    /// Unsigned integer of any number of bits, read most (big) or least (little) significant
+    /// bit first
+    BitInt(u32, Endianness),
+    Varint(Varint),
+    /// Fixed number of code units of text
+    Text(Encoding),
+    /// String terminated by a NUL code unit
+    CString(Encoding),
+    /// String prefixed by its length in code units, as an integer of the given number of bytes
+    PString(usize, Endianness, Encoding),
+    /// String in a fixed size field, padded with NUL code units
+    PaddedString(Encoding),
+    /// A type declared with ENUM or FLAGS
+    Named(String),
 }
 
+impl DType {
+    /// Number of bytes taken by a single item, or None for variable length strings
+    pub fn size(&self) -> Option<usize> {
+        let size = match self {
+            DType::U8 => 1,
+            DType::U16(_) => 2,
+            DType::U32(_) => 4,
+            DType::U64(_) => 8,
+            DType::U128(_) => 16,
+            DType::Char => 1,
+            DType::Text(encoding) => encoding.unit_size(),
+            DType::PaddedString(_) => 1,
+            DType::BitInt(..)
+            | DType::Varint(_)
+            | DType::CString(_)
+            | DType::PString(..)
+            | DType::Named(_) => return None,
+        };
+
+        Some(size)
+    }
+}
+
+impl fmt::Display for Endianness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endianness::Big => write!(f, "be"),
+            Endianness::Little => write!(f, "le"),
+        }
+    }
+}
+
+/// Formats as the suffix the encoding is written with after a string dtype
+struct EncodingSuffix(Encoding);
+
+impl fmt::Display for EncodingSuffix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Encoding::Ascii => Ok(()),
+            Encoding::Utf8 => write!(f, "_utf8"),
+            Encoding::Utf16(order) => write!(f, "_utf16{}", order),
+            Encoding::Latin1 => write!(f, "_latin1"),
+        }
+    }
+}
+
+/// Formats a dtype as it's written in a pattern
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DType::U8 => write!(f, "u8"),
+            DType::U16(order) => write!(f, "u16{}", order),
+            DType::U32(order) => write!(f, "u32{}", order),
+            DType::U64(order) => write!(f, "u64{}", order),
+            DType::U128(order) => write!(f, "u128{}", order),
+            DType::Char => write!(f, "char"),
+            DType::BitInt(width, Endianness::Big) => write!(f, "u{}", width),
+            DType::BitInt(width, order) => write!(f, "u{}{}", width, order),
+            DType::Varint(kind) => {
+                let name = match kind {
+                    Varint::Uleb128 => "uleb128",
+                    Varint::Sleb128 => "sleb128",
+                    Varint::Vlq => "vlq",
+                    Varint::ZigZag => "zigzag",
+                };
+                write!(f, "{}", name)
+            }
+            // The suffix of a plain text dtype is the whole name
+            DType::Text(encoding) => {
+                let suffix = EncodingSuffix(*encoding).to_string();
+                write!(f, "{}", suffix.trim_start_matches('_'))
+            }
+            DType::CString(encoding) => write!(f, "cstring{}", EncodingSuffix(*encoding)),
+            DType::PString(1, _, encoding) => write!(f, "pstring8{}", EncodingSuffix(*encoding)),
+            DType::PString(size, order, encoding) => {
+                write!(
+                    f,
+                    "pstring{}{}{}",
+                    size * 8,
+                    order,
+                    EncodingSuffix(*encoding)
+                )
+            }
+            DType::PaddedString(encoding) => write!(f, "string{}", EncodingSuffix(*encoding)),
+            DType::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// An expression which is evaluated against the stack at runtime
 #[derive(Debug, Clone)]
-pub enum Count {
+pub enum ValueExpr {
     Number(u64),
-    Identifier(String),
+    /// String or byte string literal
+    Bytes(Vec<u8>),
+    Variable(String),
+    Negate(Box<ValueExpr>),
+    /// Element of an array or list, counting from the end if negative
+    Index(Box<ValueExpr>, Box<ValueExpr>),
+    /// Field of a named block
+    Field(Box<ValueExpr>, String),
+    Binary(BinOp, Box<ValueExpr>, Box<ValueExpr>),
+    /// Size in bytes of a dtype
+    SizeOf(DType),
+    /// Built-in function
+    Call(String, Vec<ValueExpr>),
+}
+
+/// Formats an expression as it's written in a pattern, with nested operations in brackets
+impl fmt::Display for ValueExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Operands which are themselves operations are bracketed, so the grouping is clear
+        let operand = |x: &ValueExpr| match x {
+            ValueExpr::Binary(..) | ValueExpr::Negate(_) => format!("({})", x),
+            x => x.to_string(),
+        };
+        match self {
+            ValueExpr::Number(n) => write!(f, "{}", n),
+            ValueExpr::Bytes(bytes) => write!(f, "b\"{}\"", bytes.escape_ascii()),
+            ValueExpr::Variable(id) => write!(f, "{}", id),
+            ValueExpr::Negate(x) => write!(f, "-{}", operand(x)),
+            ValueExpr::Index(array, index) => write!(f, "{}[{}]", operand(array), index),
+            ValueExpr::Field(record, field) => write!(f, "{}.{}", operand(record), field),
+            ValueExpr::Binary(op, lhs, rhs) => {
+                write!(f, "{} {} {}", operand(lhs), op, operand(rhs))
+            }
+            ValueExpr::SizeOf(dtype) => write!(f, "sizeof({})", dtype),
+            ValueExpr::Call(name, args) => {
+                let args = args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Count {
+    Value(ValueExpr),
     Infinite,
 }
 
@@ -36,42 +384,307 @@ pub enum Expr {
         dtype: DType,
         count: Count,
         identifier: Option<String>,
+        /// Value the field must be equal to
+        expect: Option<ValueExpr>,
+        /// Algorithm and fields the field is a checksum of
+        checksum: Option<(Checksum, Vec<ValueExpr>)>,
+        /// Struct to parse the field's bytes with
+        parse_as: Option<String>,
     },
     TakeN {
         count: Count,
+        identifier: Option<String>,
+        exprs: Vec<Expr>,
+    },
+    /// A pattern which must fit within exactly the next N bytes
+    Limit {
+        size: ValueExpr,
+        identifier: Option<String>,
+        exprs: Vec<Expr>,
+    },
+    /// Apply a pattern to the decompressed or deciphered contents of a field
+    Decode {
+        transform: Transform,
+        source: ValueExpr,
+        identifier: Option<String>,
         exprs: Vec<Expr>,
     },
     TakeOver {
-        iter_identifier: String,
+        iter: ValueExpr,
         index_identifier: String,
+        identifier: Option<String>,
+        exprs: Vec<Expr>,
+    },
+    /// Bind a derived value without consuming any bytes
+    Let {
+        identifier: String,
+        value: ValueExpr,
+    },
+    /// Check that a condition holds at this point in the file
+    Assert(ValueExpr),
+    /// Names for the values or bits of an integer type
+    Enum(EnumDecl),
+    /// A named pattern which can be applied to the bytes of a field with AS
+    Struct {
+        identifier: String,
         exprs: Vec<Expr>,
     },
+    /// Skip to a multiple of some number of bytes, from the start of the file or of the
+    /// enclosing block
+    Align { size: ValueExpr, from_block: bool },
+    /// Skip some number of bytes
+    Pad(ValueExpr),
+    /// An integer broken down into runs of bits
+    Bits {
+        dtype: DType,
+        identifier: Option<String>,
+        fields: Vec<BitField>,
+    },
 }
 
-pub fn expr_parser<'a>() -> impl Parser<'a, &'a [Token], Expr, extra::Err<Rich<'a, Token>>> {
-    let dtype = select! {
-        Token::DType(x) => match x.as_str() {
-            "u8" => DType::U8,
-            "u16le" => DType::U16(Endianness::Little),
-            "u32le" => DType::U32(Endianness::Little),
-            "u64le" => DType::U64(Endianness::Little),
-            "u128le" => DType::U128(Endianness::Little),
-            "u16be" => DType::U16(Endianness::Big),
-            "u32be" => DType::U32(Endianness::Big),
-            "u64be" => DType::U64(Endianness::Big),
-            "u128be" => DType::U128(Endianness::Big),
-            "char" => DType::Char,
-            _ => panic!("Invalid dtype")
-        }
+/// An integer type with names for some of its values, or for some of its bits if it's a
+/// set of flags
+#[derive(Debug, Clone)]
+pub struct EnumDecl {
+    pub identifier: String,
+    pub dtype: DType,
+    pub variants: Vec<(String, u64)>,
+    pub flags: bool,
+}
+
+impl EnumDecl {
+    /// The name for a value, if it has one
+    pub fn name(&self, value: u128) -> Option<&str> {
+        self.variants
+            .iter()
+            .find(|(_, x)| *x as u128 == value)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The names of the flags which are set, and any set bits which don't have a name
+    pub fn flags(&self, value: u128) -> (Vec<&str>, u128) {
+        let mut rest = value;
+        let names = self
+            .variants
+            .iter()
+            .filter(|(_, mask)| *mask != 0 && value & *mask as u128 == *mask as u128)
+            .map(|(name, mask)| {
+                rest &= !(*mask as u128);
+                name.as_str()
+            })
+            .collect();
+
+        (names, rest)
+    }
+}
+
+/// A named run of bits within a BITS block
+#[derive(Debug, Clone)]
+pub struct BitField {
+    pub identifier: Option<String>,
+    pub width: u64,
+}
+
+type Extra<'a> = extra::Err<Rich<'a, Token>>;
+
+/// Accessors which can follow a value
+enum Postfix {
+    Index(ValueExpr),
+    Field(String),
+}
+
+fn parse_encoding(s: &str) -> Encoding {
+    match s {
+        "utf8" => Encoding::Utf8,
+        "utf16le" => Encoding::Utf16(Endianness::Little),
+        "utf16be" => Encoding::Utf16(Endianness::Big),
+        "latin1" => Encoding::Latin1,
+        _ => panic!("Invalid encoding"),
+    }
+}
+
+/// Parse a bit integer such as `u3` or `u12le`, which is most significant bit first by default
+fn parse_bit_int(name: &str) -> Result<DType, String> {
+    let (digits, order) = match name.strip_suffix("le") {
+        Some(digits) => (digits, Endianness::Little),
+        None => (name.strip_suffix("be").unwrap_or(name), Endianness::Big),
+    };
+    let width = digits[1..]
+        .parse()
+        .ok()
+        .filter(|width| (1..=128).contains(width))
+        .ok_or_else(|| format!("Integers must be 1 to 128 bits wide, not {:?}", name))?;
+    // Whole numbers of bytes are byte order sensitive, so they need to say which one
+    if width == 8 && digits != name {
+        return Err(format!(
+            "{:?} doesn't need an endianness suffix, use u8",
+            name
+        ));
     }
-    .labelled("dtype");
-    let count = select! {
-        Token::Number(n) => Count::Number(n),
-        Token::Identifier(id) => Count::Identifier(id),
-        Token::Wildcard => Count::Infinite,
-            
+    if width % 8 == 0 && width > 8 && digits == name {
+        return Err(format!(
+            "{:?} needs an endianness suffix, e.g. {}le or {}be",
+            name, name, name
+        ));
+    }
+
+    Ok(DType::BitInt(width, order))
+}
+
+pub fn dtype_parser<'a>() -> impl Parser<'a, &'a [Token], DType, Extra<'a>> + Clone {
+    // Invalid dtypes are reported without failing the parse, otherwise the error would be
+    // replaced by one from whichever other expression was tried at the same position
+    select! { Token::DType(x) => x }
+        .validate(|x, e, emitter| {
+            parse_dtype(&x).unwrap_or_else(|message| {
+                emitter.emit(Rich::custom(e.span(), message));
+                DType::U8
+            })
+        })
+        .labelled("dtype")
+}
+
+fn parse_dtype(x: &str) -> Result<DType, String> {
+    // Strings can have an encoding suffix, e.g. `cstring_utf16le`
+    let (name, encoding) = match x.split_once('_') {
+        Some((name, encoding)) => (name, parse_encoding(encoding)),
+        None => (x, Encoding::Ascii),
+    };
+    let dtype = match name {
+        "u8" => DType::U8,
+        "u16le" => DType::U16(Endianness::Little),
+        "u32le" => DType::U32(Endianness::Little),
+        "u64le" => DType::U64(Endianness::Little),
+        "u128le" => DType::U128(Endianness::Little),
+        "u16be" => DType::U16(Endianness::Big),
+        "u32be" => DType::U32(Endianness::Big),
+        "u64be" => DType::U64(Endianness::Big),
+        "u128be" => DType::U128(Endianness::Big),
+        "char" => DType::Char,
+        "uleb128" => DType::Varint(Varint::Uleb128),
+        "sleb128" => DType::Varint(Varint::Sleb128),
+        "vlq" => DType::Varint(Varint::Vlq),
+        "zigzag" => DType::Varint(Varint::ZigZag),
+        "utf8" | "utf16le" | "utf16be" | "latin1" => DType::Text(parse_encoding(name)),
+        "cstring" => DType::CString(encoding),
+        "pstring8" => DType::PString(1, Endianness::Little, encoding),
+        "pstring16le" => DType::PString(2, Endianness::Little, encoding),
+        "pstring32le" => DType::PString(4, Endianness::Little, encoding),
+        "pstring16be" => DType::PString(2, Endianness::Big, encoding),
+        "pstring32be" => DType::PString(4, Endianness::Big, encoding),
+        "string" => DType::PaddedString(encoding),
+        _ => parse_bit_int(name)?,
     };
 
+    Ok(dtype)
+}
+
+/// Parsers for a single value such as `records[2].size` or `(a + b)`, and for a full
+/// arithmetic expression
+pub fn value_parsers<'a>() -> (
+    impl Parser<'a, &'a [Token], ValueExpr, Extra<'a>> + Clone,
+    impl Parser<'a, &'a [Token], ValueExpr, Extra<'a>> + Clone,
+) {
+    let mut expr = Recursive::declare();
+
+    let sizeof = select! { Token::Identifier(id) if id == "sizeof" => () }
+        .ignore_then(dtype_parser().delimited_by(just(Token::LeftParen), just(Token::RightParen)))
+        .map(ValueExpr::SizeOf);
+
+    let call = select! { Token::Identifier(id) => id }
+        .then(
+            expr.clone()
+                .separated_by(just(Token::Comma))
+                .collect()
+                .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+        )
+        .map(|(name, args)| ValueExpr::Call(name, args));
+
+    let atom = choice((
+        sizeof,
+        call,
+        select! {
+            Token::Number(n) => ValueExpr::Number(n),
+            Token::Bytes(b) => ValueExpr::Bytes(b),
+            Token::Identifier(id) => ValueExpr::Variable(id),
+        },
+        expr.clone()
+            .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+    ));
+
+    let index = expr
+        .clone()
+        .delimited_by(just(Token::LeftBracket), just(Token::RightBracket))
+        .map(Postfix::Index);
+
+    let field = just(Token::Dot)
+        .ignore_then(select! { Token::Identifier(id) => id })
+        .map(Postfix::Field);
+
+    let single = atom
+        .foldl(index.or(field).repeated(), |value, postfix| match postfix {
+            Postfix::Index(index) => ValueExpr::Index(Box::new(value), Box::new(index)),
+            Postfix::Field(field) => ValueExpr::Field(Box::new(value), field),
+        })
+        .labelled("value");
+
+    let unary = just(Token::Minus)
+        .repeated()
+        .foldr(single.clone(), |_, x| ValueExpr::Negate(Box::new(x)));
+
+    let binary = |lhs, (op, rhs)| ValueExpr::Binary(op, Box::new(lhs), Box::new(rhs));
+    let product = unary.clone().foldl(
+        choice((
+            just(Token::Wildcard).to(BinOp::Mul),
+            just(Token::Slash).to(BinOp::Div),
+            just(Token::Percent).to(BinOp::Rem),
+        ))
+        .then(unary)
+        .repeated(),
+        binary,
+    );
+    let sum = product.clone().foldl(
+        choice((
+            just(Token::Plus).to(BinOp::Add),
+            just(Token::Minus).to(BinOp::Sub),
+        ))
+        .then(product)
+        .repeated(),
+        binary,
+    );
+    // Comparisons don't chain
+    let comparison = sum
+        .clone()
+        .then(
+            choice((
+                just(Token::EqualsEquals).to(BinOp::Eq),
+                just(Token::NotEquals).to(BinOp::Ne),
+                just(Token::LessThan).to(BinOp::Lt),
+                just(Token::LessEquals).to(BinOp::Le),
+                just(Token::GreaterThan).to(BinOp::Gt),
+                just(Token::GreaterEquals).to(BinOp::Ge),
+            ))
+            .then(sum)
+            .or_not(),
+        )
+        .map(move |(lhs, rhs)| match rhs {
+            Some(rhs) => binary(lhs, rhs),
+            None => lhs,
+        });
+    expr.define(comparison.labelled("expression"));
+
+    (single, expr)
+}
+
+pub fn expr_parser<'a>() -> impl Parser<'a, &'a [Token], Expr, extra::Err<Rich<'a, Token>>> {
+    let dtype = dtype_parser();
+    let (value, arithmetic) = value_parsers();
+
+    let count = value
+        .clone()
+        .map(Count::Value)
+        .or(just(Token::Wildcard).to(Count::Infinite));
+
     let maybe_identifier = select! {
         Token::Identifier(id) => Some(id),
         Token::Placeholder => None
@@ -81,42 +694,220 @@ pub fn expr_parser<'a>() -> impl Parser<'a, &'a [Token], Expr, extra::Err<Rich<'
         Token::Identifier(id) => id,
     };
 
+    let checksum = identifier.try_map(|name, span| {
+        Checksum::from_name(&name)
+            .ok_or_else(|| Rich::custom(span, format!("Unknown checksum: {}", name)))
+    });
+
+    // Declared types are referred to by name
     let primative = dtype
-        .then(count)
+        .clone()
+        .or(identifier.map(DType::Named))
+        .then(count.clone())
         .then(maybe_identifier)
-        .map(|((dtype, count), identifier)| Expr::Primative {
-            dtype,
-            count,
-            identifier,
-        });
+        .then(just(Token::Expect).ignore_then(value.clone()).or_not())
+        .then(
+            just(Token::Checksum)
+                .ignore_then(checksum)
+                .then_ignore(just(Token::Over))
+                .then(
+                    value
+                        .clone()
+                        .separated_by(just(Token::Comma))
+                        .at_least(1)
+                        .collect(),
+                )
+                .or_not(),
+        )
+        .then(just(Token::As).ignore_then(identifier).or_not())
+        .map(
+            |(((((dtype, count), identifier), expect), checksum), parse_as)| Expr::Primative {
+                dtype,
+                count,
+                identifier,
+                expect,
+                checksum,
+                parse_as,
+            },
+        );
+
+    let let_ = just(Token::Let)
+        .ignore_then(identifier)
+        .then_ignore(just(Token::Equals))
+        .then(arithmetic.clone())
+        .map(|(identifier, value)| Expr::Let { identifier, value });
+
+    let assert = just(Token::Assert)
+        .ignore_then(arithmetic.clone())
+        .map(Expr::Assert);
 
     recursive(|expr| {
+        // Blocks can optionally be named so they can be referenced later
+        let block_identifier = maybe_identifier.or_not().map(Option::flatten);
+
         let take_n = just(Token::TakeN)
             .ignore_then(count)
+            .then(block_identifier)
             .then(
                 expr.clone()
                     .repeated()
                     .collect()
                     .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
             )
-            .map(|(count, exprs)| Expr::TakeN { count, exprs });
+            .map(|((count, identifier), exprs)| Expr::TakeN {
+                count,
+                identifier,
+                exprs,
+            });
 
-        let take_over = just(Token::TakeOver)
+        let limit = just(Token::Limit)
+            .ignore_then(value.clone())
+            .then(block_identifier)
+            .then(
+                expr.clone()
+                    .repeated()
+                    .collect()
+                    .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
+            )
+            .map(|((size, identifier), exprs)| Expr::Limit {
+                size,
+                identifier,
+                exprs,
+            });
+
+        let struct_ = just(Token::Struct)
             .ignore_then(identifier)
+            .then(
+                expr.clone()
+                    .repeated()
+                    .collect()
+                    .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
+            )
+            .map(|(identifier, exprs)| Expr::Struct { identifier, exprs });
+
+        let codec = select! {
+            Token::Identifier(id) if id == "zlib" => Codec::Zlib,
+            Token::Identifier(id) if id == "deflate" => Codec::Deflate,
+            Token::Identifier(id) if id == "gzip" => Codec::Gzip,
+            Token::Identifier(id) if id == "lz4" => Codec::Lz4,
+            Token::Identifier(id) if id == "zstd" => Codec::Zstd,
+        };
+        let cipher = select! {
+            Token::Identifier(id) if id == "xor" => Cipher::Xor,
+            Token::Identifier(id) if id == "add" => Cipher::Add,
+            Token::Identifier(id) if id == "sub" => Cipher::Sub,
+            Token::Identifier(id) if id == "rc4" => Cipher::Rc4,
+        };
+        let transform = codec.map(Transform::Decompress).or(cipher
+            .then(
+                value
+                    .clone()
+                    .delimited_by(just(Token::LeftParen), just(Token::RightParen)),
+            )
+            .map(|(cipher, key)| Transform::Cipher(cipher, key)));
+
+        let decode = just(Token::Decode)
+            .ignore_then(transform)
+            .then(value.clone())
+            .then(block_identifier)
+            .then(
+                expr.clone()
+                    .repeated()
+                    .collect()
+                    .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
+            )
+            .map(|(((transform, source), identifier), exprs)| Expr::Decode {
+                transform,
+                source,
+                identifier,
+                exprs,
+            });
+
+        let take_over = just(Token::TakeOver)
+            .ignore_then(value.clone())
             .then(identifier)
+            .then(block_identifier)
             .then(
                 expr.repeated()
                     .collect()
                     .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
             )
             .map(
-                |((iter_identifier, index_identifier), exprs)| Expr::TakeOver {
-                    iter_identifier,
+                |(((iter, index_identifier), identifier), exprs)| Expr::TakeOver {
+                    iter,
                     index_identifier,
+                    identifier,
                     exprs,
                 },
             );
 
-        primative.or(take_n).or(take_over)
+        let variant = identifier
+            .then_ignore(just(Token::Equals))
+            .then(select! { Token::Number(n) => n });
+
+        let enum_ = just(Token::Enum)
+            .to(false)
+            .or(just(Token::Flags).to(true))
+            .then(identifier)
+            .then_ignore(just(Token::Colon))
+            .then(dtype.clone())
+            .then(
+                variant
+                    .separated_by(just(Token::Comma))
+                    .allow_trailing()
+                    .collect()
+                    .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
+            )
+            .map(|(((flags, identifier), dtype), variants)| {
+                Expr::Enum(EnumDecl {
+                    identifier,
+                    dtype,
+                    variants,
+                    flags,
+                })
+            });
+
+        let align = just(Token::Align)
+            .ignore_then(value.clone())
+            .then(just(Token::Block).or_not())
+            .map(|(size, block)| Expr::Align {
+                size,
+                from_block: block.is_some(),
+            });
+
+        let pad = just(Token::Pad).ignore_then(value.clone()).map(Expr::Pad);
+
+        let bit_field = maybe_identifier
+            .then_ignore(just(Token::Colon))
+            .then(select! { Token::Number(n) => n })
+            .map(|(identifier, width)| BitField { identifier, width });
+
+        let bits = just(Token::Bits)
+            .ignore_then(dtype)
+            .then(block_identifier)
+            .then(
+                bit_field
+                    .repeated()
+                    .collect()
+                    .delimited_by(just(Token::LeftBrace), just(Token::RightBrace)),
+            )
+            .map(|((dtype, identifier), fields)| Expr::Bits {
+                dtype,
+                identifier,
+                fields,
+            });
+
+        primative
+            .or(take_n)
+            .or(limit)
+            .or(decode)
+            .or(take_over)
+            .or(let_)
+            .or(assert)
+            .or(bits)
+            .or(enum_)
+            .or(struct_)
+            .or(align)
+            .or(pad)
     })
 }
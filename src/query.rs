@@ -0,0 +1,115 @@
+use anyhow::{Context, Result, bail};
+
+use crate::interpreter::{Data, Span};
+
+/// A single step along a path through the parsed tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// The child with the given identifier
+    Field(String),
+    /// The nth child
+    Index(usize),
+    /// Every child
+    Wildcard,
+}
+
+/// Parse a field path such as `chunks[3].type` or `bundles[*].name`
+pub fn parse_path(path: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut chars = path.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '[' => {
+                chars.next();
+                let index = chars.by_ref().take_while(|&c| c != ']').collect::<String>();
+                let segment = match index.trim() {
+                    "*" => Segment::Wildcard,
+                    index => Segment::Index(
+                        index
+                            .parse()
+                            .with_context(|| format!("Invalid index in path: {:?}", index))?,
+                    ),
+                };
+                segments.push(segment);
+            }
+            '.' if !segments.is_empty() => {
+                chars.next();
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                segments.push(Segment::Field(name));
+            }
+            _ => bail!("Unexpected character {:?} in path: {:?}", c, path),
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Find the nodes at the end of the given path, fanning out at wildcards
+pub fn select<'d, 'a, 'b>(data: &'d Data<'a, 'b>, path: &[Segment]) -> Vec<&'d Data<'a, 'b>> {
+    let Some((segment, rest)) = path.split_first() else {
+        return vec![data];
+    };
+
+    let (Data::List { items, .. } | Data::Decoded { items, .. }) = data else {
+        return vec![];
+    };
+    let children = match segment {
+        Segment::Field(name) => items
+            .iter()
+            .find(|d| d.identifier() == Some(name.as_str()))
+            .into_iter()
+            .collect(),
+        Segment::Index(i) => items.get(*i).into_iter().collect(),
+        Segment::Wildcard => items.iter().collect::<Vec<_>>(),
+    };
+
+    children
+        .into_iter()
+        .flat_map(|child| select(child, rest))
+        .collect()
+}
+
+/// Prune the tree down to the fields overlapping the given byte range, keeping the
+/// structure above them intact
+pub fn window<'a, 'b>(data: &Data<'a, 'b>, window: Span) -> Option<Data<'a, 'b>> {
+    let span = data.span();
+    if span.start >= window.end || window.start >= span.end {
+        return None;
+    }
+
+    match data {
+        Data::Primative { .. }
+        | Data::Virtual { .. }
+        | Data::Padding { .. }
+        | Data::Slack { .. }
+        // The items are in a different address space, so there's nothing to prune by
+        | Data::Decoded { .. }
+        | Data::Bits { .. } => Some(data.clone()),
+        Data::List {
+            span,
+            identifier,
+            items,
+        } => {
+            let items = items
+                .iter()
+                .filter_map(|d| self::window(d, window))
+                .collect::<Vec<_>>();
+
+            (!items.is_empty()).then_some(Data::List {
+                span: *span,
+                identifier: *identifier,
+                items,
+            })
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::Read;
+
+use anyhow::{Result, bail};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+use crate::parser::{Cipher, Codec};
+
+/// Decompress a whole stream, failing if it would be larger than `max_size`
+pub fn decompress(codec: Codec, bytes: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match codec {
+        Codec::Zlib => Box::new(ZlibDecoder::new(bytes)),
+        Codec::Deflate => Box::new(DeflateDecoder::new(bytes)),
+        Codec::Gzip => Box::new(GzDecoder::new(bytes)),
+        Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(bytes)),
+        Codec::Zstd => Box::new(ruzstd::decoding::StreamingDecoder::new(bytes)?),
+    };
+
+    // Read one byte past the limit to tell a stream which fits exactly from one which doesn't
+    let mut decoded = vec![];
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > max_size {
+        bail!(
+            "Decoded {} stream exceeds the allocation limit ({})",
+            codec.name(),
+            max_size
+        );
+    }
+
+    Ok(decoded)
+}
+
+/// Undo a cipher with the given key, which must not be empty
+pub fn decipher(cipher: Cipher, bytes: &[u8], key: &[u8]) -> Vec<u8> {
+    let pairs = bytes.iter().zip(key.iter().cycle());
+    match cipher {
+        Cipher::Xor => pairs.map(|(x, k)| x ^ k).collect(),
+        Cipher::Add => pairs.map(|(x, k)| x.wrapping_add(*k)).collect(),
+        Cipher::Sub => pairs.map(|(x, k)| x.wrapping_sub(*k)).collect(),
+        Cipher::Rc4 => bytes
+            .iter()
+            .zip(rc4_keystream(key))
+            .map(|(x, k)| x ^ k)
+            .collect(),
+    }
+}
+
+fn rc4_keystream(key: &[u8]) -> impl Iterator<Item = u8> {
+    // Key scheduling
+    let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    std::iter::repeat_with(move || {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(state[i as usize]);
+        state.swap(i as usize, j as usize);
+        state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+    })
+}